@@ -0,0 +1,363 @@
+use std::{collections::HashMap, ffi::OsStr, path::Path, process::Stdio, time::Duration};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio_util::{future::FutureExt, sync::CancellationToken};
+use valuable::Valuable;
+
+use crate::{pause::ChildTracker, priority, shutdown};
+
+/// Runs `FFmpeg` with the given arguments and returns everything it wrote to stderr.
+///
+/// Used for analysis passes where the interesting output is in the filter logs,
+/// not in the (discarded) encoded output.
+pub async fn ffmpeg_stderr<I, S>(args: I, ct: CancellationToken) -> anyhow::Result<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut cmd = tokio::process::Command::new("ffmpeg");
+    cmd.arg("-hide_banner")
+        .arg("-nostats")
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    priority::apply(&mut cmd);
+    shutdown::detach(&mut cmd);
+    let tracker = ChildTracker::new()?;
+    tracker.reporter().apply(&mut cmd);
+    let output = tracker
+        .track(cmd.output())
+        .with_cancellation_token(&ct)
+        .await
+        .context("Cancelled while analyzing")??;
+
+    let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+    if !output.status.success() {
+        let tail = stderr.lines().rev().take(5).collect::<Vec<_>>();
+        anyhow::bail!(
+            "ffmpeg analysis pass exited with {}: {}",
+            output.status,
+            tail.into_iter().rev().collect::<Vec<_>>().join(" / ")
+        );
+    }
+
+    Ok(stderr)
+}
+
+/// Whether the local ffmpeg has the filter `name`, which depends on the libraries it was
+/// built with
+pub async fn filter_available(name: &str) -> bool {
+    let mut cmd = tokio::process::Command::new("ffmpeg");
+    cmd.args(["-hide_banner", "-filters"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null());
+    shutdown::detach(&mut cmd);
+    let Ok(output) = cmd.output().await else {
+        return false;
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|l| l.split_whitespace().nth(1) == Some(name))
+}
+
+#[derive(Debug, Clone, Copy, Valuable)]
+pub struct LoudnessTarget {
+    /// Integrated loudness, LUFS
+    pub integrated: f64,
+    /// Maximum true peak, dBTP
+    pub true_peak: f64,
+    /// Loudness range, LU
+    pub lra: f64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Valuable)]
+pub struct LoudnessMeasurement {
+    /// Integrated loudness, LUFS
+    pub integrated: f64,
+    /// True peak, dBTP
+    pub true_peak: f64,
+    /// Loudness range, LU
+    pub lra: f64,
+    pub threshold: f64,
+    pub offset: f64,
+}
+
+// loudnorm's print_format=json output, every value is a string
+#[derive(Deserialize)]
+struct LoudnormJson {
+    input_i: String,
+    input_tp: String,
+    input_lra: String,
+    input_thresh: String,
+    target_offset: String,
+}
+
+impl LoudnessTarget {
+    fn measure_filter(&self) -> String {
+        format!(
+            "loudnorm=I={}:TP={}:LRA={}:print_format=json",
+            self.integrated, self.true_peak, self.lra
+        )
+    }
+
+    /// Second pass filter, feeding the first pass measurements back into loudnorm
+    pub fn apply_filter(&self, measured: &LoudnessMeasurement) -> String {
+        format!(
+            "loudnorm=I={}:TP={}:LRA={}:measured_I={}:measured_TP={}:measured_LRA={}:measured_thresh={}:offset={}:linear=true",
+            self.integrated,
+            self.true_peak,
+            self.lra,
+            measured.integrated,
+            measured.true_peak,
+            measured.lra,
+            measured.threshold,
+            measured.offset,
+        )
+    }
+}
+
+/// First loudnorm pass, `None` if the audio is silent and there's nothing to normalize
+pub async fn measure_loudness(
+    input: &Path,
+    target: LoudnessTarget,
+    ct: CancellationToken,
+) -> anyhow::Result<Option<LoudnessMeasurement>> {
+    let filter = target.measure_filter();
+    let stderr = ffmpeg_stderr(
+        [
+            OsStr::new("-i"),
+            input.as_os_str(),
+            OsStr::new("-vn"),
+            OsStr::new("-af"),
+            OsStr::new(&filter),
+            OsStr::new("-f"),
+            OsStr::new("null"),
+            OsStr::new("-"),
+        ],
+        ct,
+    )
+    .await?;
+
+    // The json block is the last thing loudnorm prints
+    let start = stderr
+        .rfind('{')
+        .context("loudnorm did not report any measurements")?;
+    let end = stderr[start..]
+        .find('}')
+        .context("loudnorm measurements were truncated")?;
+    let json: LoudnormJson = serde_json::from_str(&stderr[start..=start + end])
+        .context("Failed to parse loudnorm measurements")?;
+
+    let parse = |name: &str, value: &str| {
+        value
+            .trim()
+            .parse::<f64>()
+            .with_context(|| format!("Invalid loudnorm {name} value \"{value}\""))
+    };
+
+    let measured = LoudnessMeasurement {
+        integrated: parse("input_i", &json.input_i)?,
+        true_peak: parse("input_tp", &json.input_tp)?,
+        lra: parse("input_lra", &json.input_lra)?,
+        threshold: parse("input_thresh", &json.input_thresh)?,
+        offset: parse("target_offset", &json.target_offset)?,
+    };
+
+    // Silence measures as -inf
+    Ok(measured.integrated.is_finite().then_some(measured))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Valuable)]
+pub struct Crop {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+impl Crop {
+    pub fn filter(&self) -> String {
+        format!("crop={}:{}:{}:{}", self.width, self.height, self.x, self.y)
+    }
+
+    // "crop=1920:800:0:140"
+    fn parse(line: &str) -> Option<Self> {
+        let value = &line[line.rfind("crop=")? + "crop=".len()..];
+        let mut parts = value.trim().split(':').map(str::parse::<u32>);
+        Some(Self {
+            width: parts.next()?.ok()?,
+            height: parts.next()?.ok()?,
+            x: parts.next()?.ok()?,
+            y: parts.next()?.ok()?,
+        })
+    }
+}
+
+const CROP_SAMPLE_POINTS: [f64; 5] = [0.1, 0.3, 0.5, 0.7, 0.9];
+const CROP_SAMPLE_LENGTH: Duration = Duration::from_secs(2);
+
+/// Samples the input with `cropdetect` across its duration, returning the crop
+/// rectangle that a majority of samples agree on, if any
+pub async fn detect_crop(
+    input: &Path,
+    total_duration: Duration,
+    ct: CancellationToken,
+) -> anyhow::Result<Option<Crop>> {
+    // Short inputs get a single pass over the whole thing
+    let (offsets, length): (Vec<Duration>, _) =
+        if total_duration < CROP_SAMPLE_LENGTH * CROP_SAMPLE_POINTS.len() as u32 {
+            (vec![Duration::ZERO], total_duration)
+        } else {
+            (
+                CROP_SAMPLE_POINTS
+                    .iter()
+                    .map(|p| total_duration.mul_f64(*p))
+                    .collect(),
+                CROP_SAMPLE_LENGTH,
+            )
+        };
+
+    let mut votes: HashMap<Crop, usize> = HashMap::new();
+    let length = format!("{:.3}", length.as_secs_f64());
+    for offset in &offsets {
+        let start = format!("{:.3}", offset.as_secs_f64());
+        let stderr = ffmpeg_stderr(
+            [
+                OsStr::new("-ss"),
+                OsStr::new(&start),
+                OsStr::new("-t"),
+                OsStr::new(&length),
+                OsStr::new("-i"),
+                input.as_os_str(),
+                OsStr::new("-an"),
+                OsStr::new("-vf"),
+                OsStr::new("cropdetect=limit=24:round=2"),
+                OsStr::new("-f"),
+                OsStr::new("null"),
+                OsStr::new("-"),
+            ],
+            ct.child_token(),
+        )
+        .await?;
+
+        // cropdetect converges over the sample, trust the last value
+        if let Some(crop) = stderr
+            .lines()
+            .rev()
+            .filter(|l| l.contains("cropdetect"))
+            .find_map(Crop::parse)
+        {
+            *votes.entry(crop).or_default() += 1;
+        }
+    }
+
+    let Some((crop, count)) = votes.into_iter().max_by_key(|(_, count)| *count) else {
+        return Ok(None);
+    };
+
+    if count * 2 <= offsets.len() {
+        tracing::debug!(
+            crop = crop.as_value(),
+            count,
+            samples = offsets.len(),
+            "Crop detection was unstable, not cropping"
+        );
+        return Ok(None);
+    }
+
+    Ok(Some(crop))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Valuable)]
+pub enum ScanType {
+    Progressive,
+    Interlaced,
+    /// Progressive film with 3:2 pulldown applied, needs inverse telecine rather than deinterlacing
+    Telecined,
+}
+
+impl ScanType {
+    pub fn filter(self) -> Option<&'static str> {
+        match self {
+            Self::Progressive => None,
+            Self::Interlaced => Some("bwdif=mode=send_frame:parity=auto:deint=all"),
+            Self::Telecined => Some("fieldmatch,yadif=deint=interlaced,decimate"),
+        }
+    }
+}
+
+const IDET_SAMPLE_FRAMES: u32 = 1000;
+
+/// Pulls the counts following each label out of an idet summary line, e.g.
+/// "Multi frame detection: TFF:   120 BFF:     0 Progressive:   800 Undetermined:    80"
+fn idet_counts<const N: usize>(line: &str, labels: [&str; N]) -> Option<[u64; N]> {
+    let mut counts = [0; N];
+    for (count, label) in counts.iter_mut().zip(labels) {
+        let rest = &line[line.find(label)? + label.len()..];
+        *count = rest.split_whitespace().next()?.parse().ok()?;
+    }
+    Some(counts)
+}
+
+/// Runs `idet` over a sample of the input to decide whether it needs deinterlacing
+pub async fn detect_scan_type(
+    input: &Path,
+    total_duration: Duration,
+    ct: CancellationToken,
+) -> anyhow::Result<ScanType> {
+    // Skip past intros/black frames when there's enough material
+    let start = format!("{:.3}", total_duration.mul_f64(0.1).as_secs_f64());
+    let frames = IDET_SAMPLE_FRAMES.to_string();
+    let stderr = ffmpeg_stderr(
+        [
+            OsStr::new("-ss"),
+            OsStr::new(&start),
+            OsStr::new("-i"),
+            input.as_os_str(),
+            OsStr::new("-an"),
+            OsStr::new("-frames:v"),
+            OsStr::new(&frames),
+            OsStr::new("-vf"),
+            OsStr::new("idet"),
+            OsStr::new("-f"),
+            OsStr::new("null"),
+            OsStr::new("-"),
+        ],
+        ct,
+    )
+    .await?;
+
+    let [tff, bff, progressive] = stderr
+        .lines()
+        .rev()
+        .find(|l| l.contains("Multi frame detection:"))
+        .and_then(|l| idet_counts(l, ["TFF:", "BFF:", "Progressive:"]))
+        .context("idet did not report multi frame detection")?;
+    let [neither, top, bottom] = stderr
+        .lines()
+        .rev()
+        .find(|l| l.contains("Repeated Fields:"))
+        .and_then(|l| idet_counts(l, ["Neither:", "Top:", "Bottom:"]))
+        .context("idet did not report repeated fields")?;
+
+    let determined = (tff + bff + progressive).max(1) as f64;
+    let interlaced = (tff + bff) as f64 / determined;
+    let repeated = (top + bottom) as f64 / (neither + top + bottom).max(1) as f64;
+
+    tracing::debug!(tff, bff, progressive, neither, top, bottom, "idet results");
+
+    // 3:2 pulldown repeats a field in 2 of every 5 frames
+    let scan_type = if repeated > 0.15 {
+        ScanType::Telecined
+    } else if interlaced > 0.25 {
+        ScanType::Interlaced
+    } else {
+        ScanType::Progressive
+    };
+
+    Ok(scan_type)
+}
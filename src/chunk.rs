@@ -0,0 +1,127 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+
+use anyhow::Context;
+use tokio_util::{future::FutureExt, sync::CancellationToken};
+
+use crate::{pause::ChildTracker, priority, shutdown};
+
+/// How far past each even split point to look for a keyframe
+const KEYFRAME_SEARCH: Duration = Duration::from_secs(30);
+/// Segments shorter than this aren't worth an extra ffmpeg
+const MIN_SEGMENT: Duration = Duration::from_secs(30);
+
+/// First video keyframe at or after `after`, `None` if there's none within `KEYFRAME_SEARCH`.
+/// Times are relative to the start of the input, `start_time` being where that is
+async fn next_keyframe(
+    input: &Path,
+    after: Duration,
+    start_time: Duration,
+    ct: CancellationToken,
+) -> anyhow::Result<Option<Duration>> {
+    let from = (start_time + after).as_secs_f64();
+    let mut cmd = tokio::process::Command::new("ffprobe");
+    cmd.args(["-v", "error", "-select_streams", "v:0", "-read_intervals"])
+        .arg(format!("{from:.6}%+{}", KEYFRAME_SEARCH.as_secs()))
+        .args(["-show_entries", "packet=pts_time,flags", "-of", "csv=p=0"])
+        .arg(input)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true);
+    priority::apply(&mut cmd);
+    shutdown::detach(&mut cmd);
+    let tracker = ChildTracker::new()?;
+    tracker.reporter().apply(&mut cmd);
+    let output = tracker
+        .track(cmd.output())
+        .with_cancellation_token(&ct)
+        .await
+        .context("Cancelled while looking for keyframes")??;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "ffprobe exited with {} looking for keyframes in \"{}\": {}",
+            output.status,
+            input.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    // "<pts_time>,<flags>", keyframes have a K in their flags
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let (pts, flags) = line.split_once(',')?;
+            let pts: f64 = pts.parse().ok()?;
+            flags
+                .contains('K')
+                .then_some(pts - start_time.as_secs_f64())
+        })
+        .find(|pts| *pts >= after.as_secs_f64())
+        .and_then(|pts| Duration::try_from_secs_f64(pts).ok()))
+}
+
+/// Splits `total` into up to `chunks` segments of roughly equal length, cutting at the first
+/// keyframe after each even split so the segments join without gaps. Returns each segment's
+/// start and length, the last one running to the end of the input
+pub async fn split(
+    input: &Path,
+    total: Duration,
+    start_time: Duration,
+    chunks: usize,
+    ct: CancellationToken,
+) -> anyhow::Result<Vec<(Duration, Option<Duration>)>> {
+    let chunks = chunks.min((total.as_secs_f64() / MIN_SEGMENT.as_secs_f64()) as usize);
+
+    let mut starts = vec![Duration::ZERO];
+    for i in 1..chunks {
+        let even = total.mul_f64(i as f64 / chunks as f64);
+        if let Some(keyframe) = next_keyframe(input, even, start_time, ct.child_token()).await?
+            && starts.last().is_some_and(|last| keyframe > *last)
+            && keyframe < total
+        {
+            starts.push(keyframe);
+        }
+    }
+
+    Ok(starts
+        .iter()
+        .enumerate()
+        .map(|(i, start)| (*start, starts.get(i + 1).map(|next| *next - *start)))
+        .collect())
+}
+
+/// `output` with `suffix` appended. Segments add up to the size of the whole video, so
+/// they go on the output's filesystem rather than a temp dir that may be in memory
+fn beside(output: &Path, suffix: &str) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push(suffix);
+    path.into()
+}
+
+/// Scratch file for one encoded segment, e.g. "out.mp4.chunk0.mp4"
+pub fn segment_path(output: &Path, index: usize) -> PathBuf {
+    beside(output, &format!(".chunk{index}.mp4"))
+}
+
+/// Scratch concat demuxer list for the segments of `output`
+pub fn list_path(output: &Path) -> PathBuf {
+    beside(output, ".chunks.txt")
+}
+
+/// Concat demuxer list of `segments`, in order
+pub fn concat_list(segments: &[PathBuf]) -> String {
+    segments
+        .iter()
+        .map(|path| {
+            format!(
+                "file '{}'\n",
+                path.display().to_string().replace('\'', r"'\''")
+            )
+        })
+        .collect()
+}
@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+
+use serde::Serialize;
+use valuable::Valuable;
+
+use crate::OutputFormat;
+
+/// A task as it would have run
+#[derive(Debug, Serialize, Valuable)]
+pub struct DryRunTask {
+    pub id: usize,
+    pub input: PathBuf,
+    pub output: PathBuf,
+    /// Including the program name
+    pub argv: Vec<String>,
+}
+
+/// Quotes an argument for POSIX shells, leaving it alone when that's unnecessary
+fn shell_quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+pub fn print(tasks: &[DryRunTask], format: OutputFormat) -> anyhow::Result<()> {
+    match format {
+        OutputFormat::Json => println!("{}", serde_json::to_string(tasks)?),
+        OutputFormat::JsonPretty => println!("{}", serde_json::to_string_pretty(tasks)?),
+        OutputFormat::Human | OutputFormat::Verbose => {
+            for task in tasks {
+                println!(
+                    "# [{}] {} -> {}",
+                    task.id,
+                    task.input.display(),
+                    task.output.display()
+                );
+                let argv: Vec<_> = task.argv.iter().map(|a| shell_quote(a)).collect();
+                println!("{}", argv.join(" "));
+            }
+        }
+    }
+
+    Ok(())
+}
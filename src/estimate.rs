@@ -0,0 +1,123 @@
+use std::path::PathBuf;
+
+use colored::Colorize;
+use serde::Serialize;
+use valuable::Valuable;
+
+use crate::{OutputFormat, ui::format_size};
+
+/// Extrapolated output of a single task, durations in seconds and sizes in bytes
+#[derive(Debug, Serialize, Valuable)]
+pub struct Estimate {
+    pub id: usize,
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub duration: f64,
+    pub input_size: u64,
+    pub predicted_size: u64,
+    pub predicted_time: f64,
+}
+
+#[derive(Debug, Serialize, Valuable)]
+struct EstimateReport {
+    tasks: Vec<Estimate>,
+    total_input_size: u64,
+    total_predicted_size: u64,
+    /// Sum of every task's encode time
+    total_predicted_time: f64,
+    parallel: usize,
+    /// Rough wall time with `parallel` tasks running at once
+    predicted_wall_time: f64,
+}
+
+fn format_time(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    if seconds >= 3600 {
+        format!("{}h {}m", seconds / 3600, seconds % 3600 / 60)
+    } else {
+        format!("{}m {}s", seconds / 60, seconds % 60)
+    }
+}
+
+impl EstimateReport {
+    fn to_string_human(&self) -> String {
+        use std::fmt::Write;
+
+        let mut output = String::with_capacity(256);
+        let _ = writeln!(
+            output,
+            "{}",
+            format!(
+                "{:<40} {:>10} {:>12} {:>12} {:>10}",
+                "input", "duration", "input size", "est. size", "est. time"
+            )
+            .bold()
+        );
+
+        for task in &self.tasks {
+            let name = task
+                .input
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or("?");
+            let _ = writeln!(
+                output,
+                "{:<40} {:>10} {:>12} {:>12} {:>10}",
+                name,
+                format_time(task.duration),
+                format_size(task.input_size),
+                format_size(task.predicted_size),
+                format_time(task.predicted_time),
+            );
+        }
+
+        let _ = writeln!(
+            output,
+            "{}",
+            format!(
+                "{:<40} {:>10} {:>12} {:>12} {:>10}",
+                "total",
+                format_time(self.tasks.iter().map(|t| t.duration).sum()),
+                format_size(self.total_input_size),
+                format_size(self.total_predicted_size),
+                format_time(self.total_predicted_time),
+            )
+            .bold()
+        );
+        let _ = write!(
+            output,
+            "{}",
+            format!(
+                "~{} wall time with --parallel {}",
+                format_time(self.predicted_wall_time),
+                self.parallel
+            )
+            .dimmed()
+        );
+
+        output
+    }
+}
+
+pub fn print(tasks: Vec<Estimate>, parallel: usize, format: OutputFormat) -> anyhow::Result<()> {
+    let total_predicted_time: f64 = tasks.iter().map(|t| t.predicted_time).sum();
+    // Can't finish before the longest task does
+    let longest = tasks.iter().map(|t| t.predicted_time).fold(0.0, f64::max);
+    let report = EstimateReport {
+        total_input_size: tasks.iter().map(|t| t.input_size).sum(),
+        total_predicted_size: tasks.iter().map(|t| t.predicted_size).sum(),
+        total_predicted_time,
+        parallel,
+        predicted_wall_time: (total_predicted_time / parallel.max(1) as f64).max(longest),
+        tasks,
+    };
+
+    match format {
+        OutputFormat::Human => println!("{}", report.to_string_human()),
+        OutputFormat::Json => println!("{}", serde_json::to_string(&report)?),
+        OutputFormat::JsonPretty => println!("{}", serde_json::to_string_pretty(&report)?),
+        OutputFormat::Verbose => tracing::info!(report = report.as_value(), "Estimate"),
+    }
+
+    Ok(())
+}
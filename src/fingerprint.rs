@@ -0,0 +1,217 @@
+use std::{
+    collections::HashMap,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncReadExt, AsyncSeekExt},
+    sync::Mutex,
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{Args, OutputFormat, parallel::Parallelism, path, probe, queue::Order};
+
+/// Container metadata key the fingerprint is written under
+pub const TAG: &str = "ffrenc_fingerprint";
+/// Container metadata key the input's path is written under, see `source`
+pub const SOURCE_TAG: &str = "ffrenc_source";
+
+/// Bytes hashed from each end of the input. Hashing whole multi-GB inputs would cost as
+/// much as the skip saves, and size + mtime catch most changes anyway
+const SAMPLE_BYTES: u64 = 1 << 20;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+/// FNV-1a, since `DefaultHasher` may change between Rust releases
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, b| {
+        (hash ^ u64::from(*b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Stable hash of everything in `args` that affects an output. Inputs and options that
+/// only control how the batch runs are reset, so e.g. changing `--parallel` doesn't make
+/// every output stale
+pub fn settings_hash(args: &Args) -> String {
+    let settings = format!(
+        "{:?}",
+        Args {
+            input: None,
+            overwrite_output: false,
+            force: false,
+            dry_run: false,
+            retries: 0,
+            stall_timeout: None,
+            task_timeout: None,
+            fail_fast: false,
+            format: OutputFormat::Human,
+            parallel: Parallelism::Jobs(1),
+            threads: None,
+            order: Order::Input,
+            nice: None,
+            ionice: None,
+            max_load: None,
+            window: None,
+            pause_outside_window: false,
+            control_socket: None,
+            ..args.clone()
+        }
+    );
+    format!("{:016x}", fnv1a(FNV_OFFSET, settings.as_bytes()))
+}
+
+/// Identifies an input (size, mtime, and a hash of its first and last `SAMPLE_BYTES`)
+/// encoded with a profile. The profile is the settings the ffmpeg arguments are planned
+/// from rather than the arguments themselves, which depend on analysis passes as slow as
+/// the encode the fingerprint is meant to skip
+pub async fn compute(input: &Path, args: &Args) -> anyhow::Result<String> {
+    let metadata = tokio::fs::metadata(input).await?;
+    let len = metadata.len();
+    let modified = metadata
+        .modified()?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut file = tokio::fs::File::open(input).await?;
+    let mut sample = vec![0; SAMPLE_BYTES.min(len) as usize];
+    file.read_exact(&mut sample).await?;
+    let mut hash = fnv1a(FNV_OFFSET, &sample);
+    file.seek(SeekFrom::Start(len - sample.len() as u64))
+        .await?;
+    file.read_exact(&mut sample).await?;
+    hash = fnv1a(hash, &sample);
+
+    hash = fnv1a(hash, &len.to_le_bytes());
+    hash = fnv1a(hash, &modified.as_nanos().to_le_bytes());
+    hash = fnv1a(hash, settings_hash(args).as_bytes());
+    hash = fnv1a(hash, env!("CARGO_PKG_VERSION").as_bytes());
+
+    Ok(format!("{hash:016x}"))
+}
+
+/// How an input is identified in `SOURCE_TAG`, so two inputs mapping to the same output
+/// aren't mistaken for each other
+pub fn source(input: &Path) -> PathBuf {
+    std::fs::canonicalize(input).unwrap_or_else(|_| input.to_path_buf())
+}
+
+/// What an existing output was written from, see `FingerprintCache::stored`
+#[derive(Debug, Clone)]
+pub struct Stored {
+    pub fingerprint: String,
+    /// `None` for outputs written before sources were recorded
+    pub source: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    fingerprint: String,
+    #[serde(default)]
+    source: Option<PathBuf>,
+    /// The output as it was written, so a replaced output isn't mistaken for ours
+    size: u64,
+    modified: SystemTime,
+    /// `--keep-if-smaller` threw the output away, so there's nothing to compare against
+    #[serde(default)]
+    discarded: bool,
+}
+
+/// Fingerprints of outputs written by previous runs, under `path::data_root()`. Saves
+/// probing every existing output for its tag, which stays the source of truth
+#[derive(Debug)]
+pub struct FingerprintCache {
+    path: PathBuf,
+    entries: Mutex<HashMap<PathBuf, CacheEntry>>,
+}
+
+impl FingerprintCache {
+    /// Loads the cache, starting empty if it's missing or unreadable
+    pub async fn load() -> Self {
+        let path = path::fingerprints_path();
+        let entries = match tokio::fs::read(&path).await {
+            Ok(contents) => serde_json::from_slice(&contents).unwrap_or_else(|e| {
+                tracing::warn!("Ignoring unreadable fingerprint cache: {e}");
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        Self {
+            path,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// The fingerprint and source `output` was written with, or `None` if it doesn't exist
+    /// or wasn't written by ffrenc
+    pub async fn stored(&self, output: &Path, ct: CancellationToken) -> Option<Stored> {
+        let metadata = tokio::fs::metadata(output).await.ok()?;
+        if let Some(entry) = self.entries.lock().await.get(output)
+            && !entry.discarded
+            && entry.size == metadata.len()
+            && metadata.modified().is_ok_and(|m| m == entry.modified)
+        {
+            return Some(Stored {
+                fingerprint: entry.fingerprint.clone(),
+                source: entry.source.clone(),
+            });
+        }
+
+        let probe = probe::probe(output, ct).await.ok()?;
+        Some(Stored {
+            fingerprint: probe.format.tags.get(TAG)?.clone(),
+            source: probe.format.tags.get(SOURCE_TAG).map(PathBuf::from),
+        })
+    }
+
+    /// Whether `--keep-if-smaller` discarded `output` the last time it was encoded with
+    /// `fingerprint`, which would only happen again
+    pub async fn discarded(&self, output: &Path, fingerprint: &str) -> bool {
+        !output.exists()
+            && self
+                .entries
+                .lock()
+                .await
+                .get(output)
+                .is_some_and(|entry| entry.discarded && entry.fingerprint == fingerprint)
+    }
+
+    /// Remembers the fingerprint and source of a freshly written output, or that it was
+    /// discarded if it's gone
+    pub async fn record(&self, output: &Path, fingerprint: &str, source: &Path) {
+        let entry = match tokio::fs::metadata(output).await {
+            Ok(metadata) => {
+                let Ok(modified) = metadata.modified() else {
+                    return;
+                };
+                CacheEntry {
+                    fingerprint: fingerprint.to_string(),
+                    source: Some(source.to_path_buf()),
+                    size: metadata.len(),
+                    modified,
+                    discarded: false,
+                }
+            }
+            // Discarded by --keep-if-smaller
+            Err(_) => CacheEntry {
+                fingerprint: fingerprint.to_string(),
+                source: Some(source.to_path_buf()),
+                size: 0,
+                modified: SystemTime::UNIX_EPOCH,
+                discarded: true,
+            },
+        };
+
+        let mut entries = self.entries.lock().await;
+        entries.insert(output.to_path_buf(), entry);
+
+        let result =
+            async { path::write_atomic(&self.path, &serde_json::to_vec(&*entries)?).await }.await;
+        if let Err(e) = result {
+            tracing::warn!("Failed to update fingerprint cache: {e:#}");
+        }
+    }
+}
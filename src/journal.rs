@@ -0,0 +1,181 @@
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::{
+    Args,
+    fingerprint::{self, FingerprintCache, settings_hash},
+    path,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Pending,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub id: usize,
+    pub input: PathBuf,
+    pub output: PathBuf,
+    pub settings_hash: String,
+    pub state: JobState,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalData {
+    run_id: String,
+    /// The original command line, re-parsed on resume
+    argv: Vec<String>,
+    tasks: Vec<JournalEntry>,
+}
+
+/// Persistent record of a batch under `path::runs_root()`, so `ffrenc resume` can pick up
+/// where an interrupted run left off
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    data: Mutex<JournalData>,
+}
+
+impl Journal {
+    /// Starts a journal for a new run, all tasks pending
+    pub async fn create(argv: Vec<String>, entries: Vec<JournalEntry>) -> anyhow::Result<Self> {
+        let run_id = path::run_id();
+        let journal = Self {
+            path: path::runs_root().join(format!("{run_id}.json")),
+            data: Mutex::new(JournalData {
+                run_id,
+                argv,
+                tasks: entries,
+            }),
+        };
+        journal.save(&*journal.data.lock().await).await?;
+        Ok(journal)
+    }
+
+    /// Opens a run's journal, or the most recently updated one
+    pub async fn open(run_id: Option<&str>) -> anyhow::Result<Self> {
+        let path = match run_id {
+            Some(run_id) => path::runs_root().join(format!("{run_id}.json")),
+            None => latest(&path::runs_root())
+                .await?
+                .context("No runs to resume")?,
+        };
+
+        let contents = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("Failed to read journal \"{}\"", path.display()))?;
+        let data: JournalData = serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse journal \"{}\"", path.display()))?;
+
+        Ok(Self {
+            path,
+            data: Mutex::new(data),
+        })
+    }
+
+    pub async fn run_id(&self) -> String {
+        self.data.lock().await.run_id.clone()
+    }
+
+    pub async fn argv(&self) -> Vec<String> {
+        self.data.lock().await.argv.clone()
+    }
+
+    pub async fn entries(&self) -> Vec<JournalEntry> {
+        self.data.lock().await.tasks.clone()
+    }
+
+    /// Entries that still need to run. Completed tasks are skipped unless their settings
+    /// changed or their output went missing, other than to `--keep-if-smaller`; anything
+    /// else is restarted from scratch, with whatever it left behind removed
+    pub async fn resumable(
+        &self,
+        args: &Args,
+        fingerprints: &FingerprintCache,
+    ) -> anyhow::Result<Vec<JournalEntry>> {
+        let settings_hash = settings_hash(args);
+        let mut entries = vec![];
+        for entry in self.entries().await {
+            if entry.state == JobState::Completed && entry.settings_hash == settings_hash {
+                if entry.output.exists() {
+                    tracing::info!(task_id = entry.id, "Already completed, skipping");
+                    continue;
+                }
+                if let Ok(fingerprint) = fingerprint::compute(&entry.input, args).await
+                    && fingerprints.discarded(&entry.output, &fingerprint).await
+                {
+                    tracing::info!(
+                        task_id = entry.id,
+                        "Output was discarded by --keep-if-smaller, skipping"
+                    );
+                    continue;
+                }
+            }
+
+            if entry.output.exists() {
+                tokio::fs::remove_file(&entry.output)
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Failed to remove partial output \"{}\"",
+                            entry.output.display()
+                        )
+                    })?;
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Records a task's state. Failing to persist it only costs a redo on resume, so errors
+    /// are logged rather than failing the task
+    pub async fn set_state(&self, task_id: usize, state: JobState) {
+        let mut data = self.data.lock().await;
+        let Some(entry) = data.tasks.iter_mut().find(|e| e.id == task_id) else {
+            return;
+        };
+        entry.state = state;
+
+        if let Err(e) = self.save(&data).await {
+            tracing::warn!(task_id, "Failed to update journal: {e:#}");
+        }
+    }
+
+    async fn save(&self, data: &JournalData) -> anyhow::Result<()> {
+        path::write_atomic(&self.path, &serde_json::to_vec_pretty(data)?).await
+    }
+}
+
+async fn latest(root: &Path) -> anyhow::Result<Option<PathBuf>> {
+    let mut dir = match tokio::fs::read_dir(root).await {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut latest: Option<(SystemTime, PathBuf)> = None;
+    while let Some(entry) = dir.next_entry().await? {
+        let path = entry.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        let modified = entry.metadata().await?.modified()?;
+        if latest.as_ref().is_none_or(|(m, _)| modified > *m) {
+            latest = Some((modified, path));
+        }
+    }
+
+    Ok(latest.map(|(_, path)| path))
+}
@@ -1,21 +1,50 @@
 use std::io::BufRead;
+use std::process::ExitCode;
 use std::sync::Arc;
 use std::{path::PathBuf, time::Duration};
 
 use anyhow::bail;
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use tokio::task::JoinSet;
 use tokio_util::sync::CancellationToken;
 use tracing::{Instrument, info_span};
 use valuable::Valuable;
 
-use crate::tasks::{SharedTaskContext, Task};
+use crate::fingerprint::FingerprintCache;
+use crate::journal::{JobState, Journal, JournalEntry};
+use crate::parallel::Parallelism;
+use crate::pause::PauseControl;
+use crate::priority::IoPriority;
+use crate::quality::Metric;
+use crate::queue::{Order, TaskQueue};
+use crate::search::QualityGoal;
+use crate::target::Target;
+use crate::tasks::{SharedTaskContext, Task, TaskOutcome};
 use crate::ui::ui_spawn;
-
+use crate::window::TimeWindow;
+
+mod analyze;
+mod chunk;
+mod dry_run;
+mod estimate;
+mod fingerprint;
+mod journal;
 mod log;
+mod parallel;
 mod path;
+mod pause;
+mod plan;
+mod priority;
+mod probe;
+mod quality;
+mod queue;
+mod search;
+mod shutdown;
+mod target;
 mod tasks;
 mod ui;
+mod verify;
+mod window;
 
 #[derive(ValueEnum, Debug, Clone, Copy, Valuable)]
 pub enum OutputFormat {
@@ -25,14 +54,75 @@ pub enum OutputFormat {
     JsonPretty,
 }
 
+#[derive(ValueEnum, Debug, Clone, Copy, Valuable)]
+pub enum Mp4Mode {
+    /// Move the moov atom to the front once encoding finishes
+    Faststart,
+    /// Fragmented output, playable while being written and tolerant of crashes
+    Fragmented,
+    /// Plain mp4, moov atom at the end
+    Standard,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Valuable)]
+pub enum IfLarger {
+    /// Delete the output
+    Discard,
+    /// Replace the output with a stream copy of the input
+    Copy,
+    /// Encode again with a slower, more efficient preset, discarding if that's still too large
+    Reencode,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Valuable)]
+pub enum Deinterlace {
+    /// Sample the input with idet and deinterlace/inverse telecine only when needed
+    Auto,
+    /// Always deinterlace
+    Always,
+    /// Never deinterlace
+    Never,
+}
+
+impl Mp4Mode {
+    pub fn movflags(self) -> Option<&'static str> {
+        match self {
+            Self::Faststart => Some("+faststart"),
+            Self::Fragmented => Some("+frag_keyframe+empty_moov"),
+            Self::Standard => None,
+        }
+    }
+}
+
+/// Process exit codes, so scripts can tell how a batch went. Setup errors (bad args,
+/// unreadable inputs, ...) exit with 1 through `main`'s error
+#[derive(Debug, Clone, Copy)]
+enum Exit {
+    Success = 0,
+    PartialFailure = 2,
+    AllFailed = 3,
+    Cancelled = 130,
+}
+
+impl From<Exit> for ExitCode {
+    fn from(exit: Exit) -> Self {
+        ExitCode::from(exit as u8)
+    }
+}
+
+/// How long the UI gets to print its summary once every task has exited
+const UI_EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
 /// ffmpeg wrapper to reencode video files, with some options
 
-#[derive(Parser, Debug, Valuable, Clone)]
+#[derive(Parser, Debug)]
 #[command(
     version,
     author,
     about,
     long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
     help_template = "\
 {name} ({version})
 {author-with-newline}
@@ -41,10 +131,31 @@ pub enum OutputFormat {
 
 {all-args}"
 )]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Encode a few samples of each input to estimate output size and encoding time
+    Estimate(Args),
+    /// Rerun an interrupted batch, skipping completed tasks and restarting the rest
+    Resume {
+        /// Run to resume, defaults to the most recently updated one
+        run_id: Option<String>,
+    },
+}
+
+#[derive(clap::Args, Debug, Valuable, Clone)]
 pub struct Args {
     /// Input path. Use '-' to read a list of paths from stdin (one per line)
-    #[arg(short, long)]
-    input: String,
+    // Only optional so subcommands can skip it, clap still requires it wherever `Args` is used
+    #[arg(short, long, required = true)]
+    input: Option<String>,
 
     /// Output path pattern. Use {SLUG} as placeholder for input filename without extension
     #[arg(short, long, default_value = "{SLUG}.renc.mp4")]
@@ -54,6 +165,48 @@ pub struct Args {
     #[arg(long)]
     no_audio: bool,
 
+    /// Normalize audio loudness with a two-pass loudnorm (forces an audio transcode)
+    #[arg(long, conflicts_with = "no_audio")]
+    normalize_audio: bool,
+
+    /// Integrated loudness target for --normalize-audio, in LUFS
+    #[arg(long, default_value_t = -16.0, allow_negative_numbers = true)]
+    target_lufs: f64,
+
+    /// Maximum true peak for --normalize-audio, in dBTP
+    #[arg(long, default_value_t = -1.5, allow_negative_numbers = true)]
+    target_true_peak: f64,
+
+    /// Loudness range target for --normalize-audio, in LU
+    #[arg(long, default_value_t = 11.0)]
+    target_lra: f64,
+
+    /// Detect and crop away black bars (letterboxing/pillarboxing)
+    #[arg(long, conflicts_with = "no_video")]
+    auto_crop: bool,
+
+    /// Deinterlace the video. `auto` detects interlaced and telecined sources
+    #[arg(long, value_enum, default_value_t=Deinterlace::Never)]
+    deinterlace: Deinterlace,
+
+    /// Tonemap HDR (PQ/HLG) inputs to BT.709 SDR. HDR is preserved otherwise
+    #[arg(long, conflicts_with = "no_video")]
+    tonemap: bool,
+
+    /// Force 8-bit yuv420p limited range output that plays everywhere (tonemaps HDR inputs)
+    #[arg(long, conflicts_with = "no_video")]
+    compat: bool,
+
+    /// Constrain the output to what a playback target supports, and validate it afterwards
+    #[arg(long, value_enum)]
+    target: Option<Target>,
+
+    /// Split each input at keyframes into up to N segments and encode them concurrently,
+    /// each taking a --parallel slot, for speeding up single long inputs. Not with --dry-run,
+    /// which prints a single command per task
+    #[arg(long, value_name = "N", conflicts_with_all = ["no_video", "dry_run"])]
+    chunks: Option<usize>,
+
     /// Disable video encoding
     #[arg(long)]
     no_video: bool,
@@ -62,13 +215,124 @@ pub struct Args {
     #[arg(short = 'y', long = "overwrite")]
     overwrite_output: bool,
 
+    /// Encode even if the output is up to date with the input and settings
+    #[arg(long)]
+    force: bool,
+
+    /// Mp4 container layout. Use fragmented when streaming or to keep partial output on crash
+    /// [default: faststart, or what --target uses]
+    #[arg(long, value_enum)]
+    mp4_mode: Option<Mp4Mode>,
+
+    /// Skip probing outputs after encoding to check their duration, streams and codecs
+    #[arg(long)]
+    no_verify: bool,
+
+    /// Delete outputs that fail verification. The input is never touched either way
+    #[arg(long)]
+    remove_invalid: bool,
+
+    /// Only keep outputs smaller than the input (times RATIO, default 1.0)
+    #[arg(long, value_name = "RATIO", num_args = 0..=1, default_missing_value = "1.0")]
+    keep_if_smaller: Option<f64>,
+
+    /// What to do with outputs that fail --keep-if-smaller
+    #[arg(long, value_enum, default_value_t=IfLarger::Discard)]
+    if_larger: IfLarger,
+
+    /// Print the ffmpeg command for each task instead of running it. Probing and analysis
+    /// passes still run, since the command depends on them, but not the encodes a
+    /// --target-quality search needs
+    #[arg(long, conflicts_with = "target_quality")]
+    dry_run: bool,
+
+    /// Compare each output against its input after encoding (comma separated)
+    #[arg(long, value_enum, value_delimiter = ',')]
+    measure: Vec<Metric>,
+
+    /// Search for the crf that hits a quality score, e.g. ssim:0.98 or vmaf:95
+    #[arg(long, value_name = "METRIC:SCORE", conflicts_with = "no_video")]
+    target_quality: Option<QualityGoal>,
+
+    /// Fail tasks whose SSIM is below this (requires --measure ssim)
+    #[arg(long)]
+    min_ssim: Option<f64>,
+
+    /// Fail tasks whose PSNR is below this, in dB (requires --measure psnr)
+    #[arg(long)]
+    min_psnr: Option<f64>,
+
+    /// Fail tasks whose VMAF is below this (requires --measure vmaf)
+    #[arg(long)]
+    min_vmaf: Option<f64>,
+
+    /// Retry failed encodes this many times, with exponential backoff
+    #[arg(long, default_value_t = 0)]
+    retries: usize,
+
+    /// Retry with the same settings, instead of falling back to transcoding audio and then
+    /// ignoring decode errors
+    #[arg(long)]
+    no_fallback: bool,
+
+    /// Kill encodes that make no progress for this many seconds
+    #[arg(long, value_name = "SECS")]
+    stall_timeout: Option<u64>,
+
+    /// Kill encodes that take longer than FACTOR times the input's duration, e.g. 3 for three
+    /// times realtime
+    #[arg(long, value_name = "FACTOR")]
+    task_timeout: Option<f64>,
+
+    /// Cancel the remaining tasks as soon as one fails
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Listen for "pause", "resume" and "toggle" commands on this unix socket
+    #[arg(long, value_name = "PATH")]
+    control_socket: Option<PathBuf>,
+
     /// Output format for progress and status information
     #[arg(short, long, value_enum, default_value_t=OutputFormat::Human)]
     format: OutputFormat,
 
-    /// Max concurrent tasks
-    #[arg(short, long, default_value_t = 1)]
-    parallel: usize,
+    /// Max concurrent tasks, or "auto" to size to the cores and available memory
+    #[arg(short, long, default_value = "1")]
+    parallel: Parallelism,
+
+    /// Run ffmpeg at this niceness (default 10 when given without a value)
+    #[arg(
+        long,
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "10",
+        allow_negative_numbers = true
+    )]
+    nice: Option<i32>,
+
+    /// Run ffmpeg in a lower IO scheduling class (Linux)
+    #[arg(long, value_enum)]
+    ionice: Option<IoPriority>,
+
+    /// Don't start new tasks while the 1-minute load average is above this
+    #[arg(long, value_name = "LOAD")]
+    max_load: Option<f64>,
+
+    /// Only start tasks inside this daily local time window, e.g. 22:00-07:00
+    #[arg(long, value_name = "HH:MM-HH:MM")]
+    window: Option<TimeWindow>,
+
+    /// Pause running tasks while outside --window, instead of letting them finish
+    #[arg(long, requires = "window")]
+    pause_outside_window: bool,
+
+    /// Which queued task starts next
+    #[arg(long, value_enum, default_value_t=Order::Input)]
+    order: Order,
+
+    /// Total ffmpeg threads, split evenly across concurrent tasks (default: all cores)
+    #[arg(long)]
+    threads: Option<usize>,
 
     /// Additional arguments to pass directly to `FFmpeg`
     #[allow(clippy::struct_field_names)]
@@ -77,101 +341,312 @@ pub struct Args {
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+async fn main() -> anyhow::Result<ExitCode> {
+    let cli = Cli::parse();
+    let (mut args, estimate, journal) = match cli.command {
+        Some(Command::Estimate(args)) => (args, true, None),
+        Some(Command::Resume { run_id }) => {
+            let journal = Journal::open(run_id.as_deref()).await?;
+            let Cli {
+                command: None,
+                args,
+            } = Cli::try_parse_from(journal.argv().await)?
+            else {
+                bail!(
+                    "Run {} is not a batch, nothing to resume",
+                    journal.run_id().await
+                );
+            };
+            (args, false, Some(Arc::new(journal)))
+        }
+        None => (cli.args, false, None),
+    };
+    let input = args.input.clone().expect("clap requires --input");
     log::register_tracing_subscriber(!matches!(&args.format, OutputFormat::Verbose));
 
     let span = info_span!("ffrenc::main").entered();
 
+    for (threshold, metric, name) in [
+        (args.min_ssim, Metric::Ssim, "ssim"),
+        (args.min_psnr, Metric::Psnr, "psnr"),
+        (args.min_vmaf, Metric::Vmaf, "vmaf"),
+    ] {
+        if threshold.is_some() && !args.measure.contains(&metric) {
+            anyhow::bail!("--min-{name} requires --measure {name}");
+        }
+    }
+
+    if args.measure.contains(&Metric::Vmaf) && !quality::vmaf_available().await {
+        if args.min_vmaf.is_some() {
+            anyhow::bail!("--min-vmaf requires an ffmpeg built with libvmaf");
+        }
+        tracing::warn!("ffmpeg was built without libvmaf, not measuring VMAF");
+        args.measure.retain(|m| *m != Metric::Vmaf);
+    }
+    if args
+        .target_quality
+        .is_some_and(|goal| goal.metric == Metric::Vmaf)
+        && !quality::vmaf_available().await
+    {
+        anyhow::bail!("--target-quality vmaf requires an ffmpeg built with libvmaf");
+    }
+    if args.tonemap && !plan::zscale_available().await {
+        anyhow::bail!("--tonemap requires an ffmpeg built with libzimg (zscale)");
+    }
+
+    priority::set(args.nice, args.ionice)?;
+    if args.max_load.is_some() && priority::load_average().is_none() {
+        tracing::warn!("Can't read the load average, ignoring --max-load");
+        args.max_load = None;
+    }
+
+    // Resolved once, so every use agrees even if available memory changes
+    let jobs = args.parallel.jobs();
+    args.parallel = Parallelism::Jobs(jobs);
+    tracing::info!(jobs, "Running up to {jobs} tasks at once");
+
     let cancellation_token = CancellationToken::new();
-    libsignal::cancel_after_signal(cancellation_token.clone());
+    // Stops new tasks from starting, cancelled along with `cancellation_token`
+    let drain_token = cancellation_token.child_token();
+    shutdown::handle_signals(drain_token.clone(), cancellation_token.clone());
+
+    let fingerprints = Arc::new(FingerprintCache::load().await);
+    let pause = Arc::new(PauseControl::new());
+
+    let task_specs = if let Some(journal) = &journal {
+        let specs: Vec<_> = journal
+            .resumable(&args, &fingerprints)
+            .await?
+            .into_iter()
+            .map(|e| (e.id, e.input, e.output))
+            .collect();
+        if specs.is_empty() {
+            println!("Run {} already completed", journal.run_id().await);
+            return Ok(ExitCode::SUCCESS);
+        }
+        specs
+    } else {
+        let mut inputs = vec![];
 
-    let mut inputs = vec![];
+        if &input == "-" {
+            let stdin = std::io::stdin();
+            let stdin = stdin.lock();
+            let mut stdin = stdin.lines();
 
-    if &args.input == "-" {
-        let stdin = std::io::stdin();
-        let stdin = stdin.lock();
-        let mut stdin = stdin.lines();
+            while let Some(line) = stdin.next().transpose()? {
+                if line.trim().is_empty() {
+                    continue;
+                }
 
-        while let Some(line) = stdin.next().transpose()? {
-            if line.trim().is_empty() {
-                continue;
+                match PathBuf::from(&line).canonicalize() {
+                    Ok(path) => inputs.push(path),
+                    Err(e) => {
+                        tracing::warn!("Unable to canonicalize input path {line}: {e}");
+                    }
+                }
             }
+        } else {
+            inputs.push(PathBuf::from(&input).canonicalize()?);
+        }
 
-            match PathBuf::from(&line).canonicalize() {
-                Ok(path) => inputs.push(path),
-                Err(e) => {
-                    tracing::warn!("Unable to canonicalize input path {line}: {e}");
+        if inputs.is_empty() {
+            anyhow::bail!("No inputs specified");
+        }
+
+        tracing::info!(
+            inputs = inputs.as_value(),
+            "Validated {} inputs",
+            inputs.len()
+        );
+
+        let specs = inputs
+            .into_iter()
+            .enumerate()
+            .map(|(id, input)| {
+                let slug = input
+                    .file_stem()
+                    .expect("Failed to find file stem")
+                    .to_str()
+                    .expect("Failed to convert file stem to string")
+                    .to_string();
+
+                let output = std::env::current_dir()?.join(args.output.replace("{SLUG}", &slug));
+                Ok((id, input, output))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        // Estimates don't write anything. Outputs a previous run wrote from the same input
+        // are either up to date and skipped, or stale and replaced
+        if !estimate && !args.overwrite_output {
+            for (_, input, output) in &specs {
+                if output.exists()
+                    && fingerprints
+                        .stored(output, cancellation_token.child_token())
+                        .await
+                        .is_none_or(|stored| stored.source != Some(fingerprint::source(input)))
+                {
+                    bail!(
+                        "Output file (\"{}\") already exists (-y/--overwrite to overwrite)",
+                        output.display()
+                    )
                 }
             }
         }
-    } else {
-        inputs.push(PathBuf::from(&args.input).canonicalize()?);
+
+        specs
+    };
+
+    let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+    if estimate {
+        drop(rx);
+
+        let cx = Arc::new(SharedTaskContext::new(
+            tx,
+            TaskQueue::new(args.parallel.jobs(), args.order),
+            cancellation_token.child_token(),
+            drain_token.clone(),
+            None,
+            fingerprints.clone(),
+            pause.clone(),
+        ));
+        let mut estimates = Vec::with_capacity(task_specs.len());
+        for (id, input, output) in task_specs {
+            let task = Task::new(id, input, output, args.clone(), cx.clone()).await?;
+            estimates.push(task.estimate().await?);
+        }
+        estimate::print(estimates, args.parallel.jobs(), args.format)?;
+        return Ok(ExitCode::SUCCESS);
     }
 
-    if inputs.is_empty() {
-        anyhow::bail!("No inputs specified");
+    if args.dry_run {
+        // Nothing is listening, don't let analysis updates fill the channel
+        drop(rx);
+
+        let cx = Arc::new(SharedTaskContext::new(
+            tx,
+            TaskQueue::new(args.parallel.jobs(), args.order),
+            cancellation_token.child_token(),
+            drain_token.clone(),
+            None,
+            fingerprints.clone(),
+            pause.clone(),
+        ));
+        let mut planned = Vec::with_capacity(task_specs.len());
+        for (id, input, output) in task_specs {
+            let task = Task::new(id, input, output, args.clone(), cx.clone()).await?;
+            planned.push(task.dry_run().await?);
+        }
+        dry_run::print(&planned, args.format)?;
+        return Ok(ExitCode::SUCCESS);
     }
 
+    let journal = match journal {
+        Some(journal) => journal,
+        None => {
+            let settings_hash = fingerprint::settings_hash(&args);
+            let entries = task_specs
+                .iter()
+                .map(|(id, input, output)| JournalEntry {
+                    id: *id,
+                    input: input.clone(),
+                    output: output.clone(),
+                    settings_hash: settings_hash.clone(),
+                    state: JobState::Pending,
+                })
+                .collect();
+            Arc::new(Journal::create(std::env::args().collect(), entries).await?)
+        }
+    };
     tracing::info!(
-        inputs = inputs.as_value(),
-        "Validated {} inputs",
-        inputs.len()
+        run_id = journal.run_id().await,
+        "Journaling run, continue it with `ffrenc resume` if interrupted"
     );
 
-    let task_specs = inputs
-        .into_iter()
-        .map(|input| {
-            let slug = input
-                .file_stem()
-                .expect("Failed to find file stem")
-                .to_str()
-                .expect("Failed to convert file stem to string")
-                .to_string();
-
-            let output = std::env::current_dir()?.join(args.output.replace("{SLUG}", &slug));
-
-            if !args.overwrite_output && output.exists() {
-                bail!(
-                    "Output file (\"{}\") already exists (-y/--overwrite to overwrite)",
-                    output.display()
-                )
-            }
-
-            Ok((input, output))
-        })
-        .collect::<Result<Vec<_>, _>>()?;
+    pause::spawn_enforcer(pause.clone(), cancellation_token.child_token());
+    pause::spawn_signal_trigger(pause.clone());
+    if let Some(window) = args.window
+        && args.pause_outside_window
+    {
+        window::spawn_pauser(window, pause.clone(), cancellation_token.child_token());
+    }
+    if let Some(path) = args.control_socket.clone() {
+        pause::spawn_control_socket(pause.clone(), path, cancellation_token.child_token())?;
+    }
+    // Restores the terminal when main returns
+    let _terminal = if matches!(args.format, OutputFormat::Human) && input != "-" {
+        pause::spawn_keypress_trigger(pause.clone())
+    } else {
+        None
+    };
 
-    let (tx, rx) = tokio::sync::mpsc::channel(100);
     let mut tasks = JoinSet::new();
 
     let cx = Arc::new(SharedTaskContext::new(
         tx,
-        args.parallel,
+        TaskQueue::new(args.parallel.jobs(), args.order),
         cancellation_token.child_token(),
+        drain_token.clone(),
+        Some(journal),
+        fingerprints,
+        pause.clone(),
     ));
 
-    for (id, (input, output)) in task_specs.into_iter().enumerate() {
-        let task = Task::new(id, input, output, args.clone(), cx.clone()).await?;
+    for (id, input, output) in task_specs {
+        // Interrupted while probing, the journal still has the rest for `ffrenc resume`
+        if drain_token.is_cancelled() {
+            break;
+        }
+        let task =
+            match Task::new(id, input.clone(), output.clone(), args.clone(), cx.clone()).await {
+                Ok(task) => task,
+                Err(error) => {
+                    tasks.spawn(
+                        cx.clone()
+                            .fail_unstarted(id, input, output, error, args.fail_fast)
+                            .instrument(span.clone()),
+                    );
+                    continue;
+                }
+            };
         tracing::debug!(task = task.as_value(), "Enqueued task");
-        tasks.spawn(task.run().instrument(span.clone()));
+        let ticket = task.enqueue().await;
+        tasks.spawn(task.run(ticket).instrument(span.clone()));
     }
+    cx.open_queue();
 
-    let (ui_token, ui_handle) = ui_spawn(
-        rx,
-        cancellation_token.child_token(),
-        args.format,
-        span.clone(),
-    );
+    // Not tied to signals, the UI keeps reporting while running tasks wind down
+    let (ui_token, ui_handle) = ui_spawn(rx, CancellationToken::new(), args.format, span.clone());
 
-    tasks.join_all().await;
+    let outcomes: Vec<TaskOutcome> = tasks
+        .join_all()
+        .await
+        .into_iter()
+        .map(|r| r.unwrap_or(TaskOutcome::Failed))
+        .collect();
     ui_token.cancel();
 
-    tokio::time::timeout(Duration::from_secs(1), ui_handle)
-        .await
-        .expect("Timed out waiting for UI to exit")
-        .expect("Timed out waiting for UI to exit 2?")
-        .expect("UI Exited unsuccessfully");
+    match tokio::time::timeout(UI_EXIT_TIMEOUT, ui_handle).await {
+        Ok(Ok(Ok(()))) => {}
+        Ok(Ok(Err(e))) => tracing::warn!("UI exited unsuccessfully: {e:#}"),
+        Ok(Err(e)) => tracing::warn!("UI task panicked: {e}"),
+        Err(_) => tracing::warn!("Timed out waiting for the UI to exit"),
+    }
+
+    let failed = outcomes
+        .iter()
+        .filter(|o| **o == TaskOutcome::Failed)
+        .count();
+    let exit = if drain_token.is_cancelled() {
+        Exit::Cancelled
+    } else if failed == 0 {
+        Exit::Success
+    } else if failed == outcomes.len() {
+        Exit::AllFailed
+    } else {
+        Exit::PartialFailure
+    };
+    tracing::info!(failed, total = outcomes.len(), "Exiting with {exit:?}");
 
-    Ok(())
+    Ok(exit.into())
 }
@@ -0,0 +1,67 @@
+use std::str::FromStr;
+
+use valuable::Valuable;
+
+/// Cores given to each task under `--parallel auto`. x264 stops scaling well past a handful
+/// of threads at the resolutions we encode, more jobs use the cores better
+const THREADS_PER_JOB: usize = 4;
+/// Rough peak memory of one 1080p x264 encode plus its filters
+const MEMORY_PER_JOB: u64 = 1_500_000_000;
+
+/// `--parallel`, a number of concurrent tasks or "auto"
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Valuable)]
+pub enum Parallelism {
+    Auto,
+    Jobs(usize),
+}
+
+impl FromStr for Parallelism {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("auto") {
+            return Ok(Self::Auto);
+        }
+        match s.parse() {
+            Ok(0) => Err("must be at least 1".to_string()),
+            Ok(jobs) => Ok(Self::Jobs(jobs)),
+            Err(e) => Err(format!("expected a number or \"auto\", got \"{s}\": {e}")),
+        }
+    }
+}
+
+impl Parallelism {
+    /// Number of concurrent tasks, with `Auto` sized to the cores and available memory
+    pub fn jobs(self) -> usize {
+        match self {
+            Self::Jobs(jobs) => jobs,
+            Self::Auto => {
+                let by_cores = available_cores() / THREADS_PER_JOB;
+                let by_memory = available_memory()
+                    .map_or(usize::MAX, |bytes| (bytes / MEMORY_PER_JOB) as usize);
+                by_cores.min(by_memory).max(1)
+            }
+        }
+    }
+}
+
+pub fn available_cores() -> usize {
+    std::thread::available_parallelism().map_or(1, std::num::NonZero::get)
+}
+
+/// `MemAvailable` from /proc/meminfo, `None` where that doesn't exist
+fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemAvailable:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
+
+/// Threads each task's ffmpeg gets, splitting `budget` (all cores by default) across `jobs`
+/// concurrent tasks. `None` leaves a lone task to ffmpeg's defaults
+pub fn threads_per_task(budget: Option<usize>, jobs: usize) -> Option<usize> {
+    if budget.is_none() && jobs <= 1 {
+        return None;
+    }
+    Some((budget.unwrap_or_else(available_cores) / jobs.max(1)).max(1))
+}
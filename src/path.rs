@@ -1,4 +1,7 @@
-use std::{path::PathBuf, time::SystemTime};
+use std::{
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
 
 const PRODUCT_NAME: &str = "dev.thmsn.ffrenc";
 
@@ -27,3 +30,28 @@ pub fn logs_path() -> PathBuf {
     }
     parent.join(format!("{}_log.json", epoch()))
 }
+
+pub fn runs_root() -> PathBuf {
+    data_root().join("runs")
+}
+
+/// A new run id, unique to this process
+pub fn run_id() -> String {
+    format!("{}-{}", epoch(), std::process::id())
+}
+
+pub fn fingerprints_path() -> PathBuf {
+    data_root().join("fingerprints.json")
+}
+
+/// Writes to a temporary file first, so a crash mid-write can't leave a corrupt file behind
+pub async fn write_atomic(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    tokio::fs::write(&tmp, contents).await?;
+    tokio::fs::rename(&tmp, path).await?;
+    Ok(())
+}
@@ -0,0 +1,410 @@
+use std::{
+    collections::BTreeSet,
+    io::{IsTerminal, Read},
+    path::PathBuf,
+    process::Stdio,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+/// How often SIGSTOP is re-sent while paused, to catch children spawned since
+const STOP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Shared paused/running switch. Tasks don't start while paused, and `spawn_enforcer` keeps
+/// every ffmpeg child stopped
+#[derive(Debug)]
+pub struct PauseControl {
+    tx: watch::Sender<bool>,
+    sources: Mutex<PauseSources>,
+}
+
+/// Who wants the batch paused, it runs only when neither does
+#[derive(Debug, Default)]
+struct PauseSources {
+    /// Keypress, signal or control socket
+    user: bool,
+    /// `--pause-outside-window`
+    window: bool,
+}
+
+impl PauseSources {
+    fn paused(&self) -> bool {
+        self.user || self.window
+    }
+
+    /// A resume from the user wins over the window too, it'll pause again when it next closes
+    fn set_user(&mut self, paused: bool) {
+        self.user = paused;
+        if !paused {
+            self.window = false;
+        }
+    }
+}
+
+impl Default for PauseControl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PauseControl {
+    pub fn new() -> Self {
+        Self {
+            tx: watch::Sender::new(false),
+            sources: Mutex::new(PauseSources::default()),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.tx.subscribe()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut PauseSources)) {
+        let mut sources = self.sources.lock().expect("pause lock poisoned");
+        f(&mut sources);
+        let paused = sources.paused();
+        self.tx.send_if_modified(|current| {
+            let changed = *current != paused;
+            *current = paused;
+            changed
+        });
+    }
+
+    pub fn set(&self, paused: bool) {
+        self.update(|sources| sources.set_user(paused));
+    }
+
+    pub fn toggle(&self) {
+        self.update(|sources| sources.set_user(!sources.paused()));
+    }
+
+    /// Pauses while the `--window` is closed, leaving pauses from the user alone
+    pub fn set_window_closed(&self, closed: bool) {
+        self.update(|sources| sources.window = closed);
+    }
+
+    /// Returns immediately unless paused
+    pub async fn wait_resumed(&self) {
+        let _ = self.subscribe().wait_for(|paused| !paused).await;
+    }
+}
+
+/// Running ffmpeg/ffprobe children, see `ChildTracker`. Each leads its own process group,
+/// see `shutdown::detach`
+#[cfg(unix)]
+static CHILDREN: Mutex<BTreeSet<libc::pid_t>> = Mutex::new(BTreeSet::new());
+
+/// Stops or continues every child in `CHILDREN`
+#[cfg(unix)]
+fn signal_children(stop: bool) {
+    let signal = if stop { libc::SIGSTOP } else { libc::SIGCONT };
+    for pid in CHILDREN.lock().expect("children lock poisoned").iter() {
+        // The whole group, in case ffmpeg has children of its own. Already exited is fine
+        unsafe {
+            libc::killpg(*pid, signal);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn signal_children(_stop: bool) {}
+
+/// Keeps a child in `CHILDREN` while it runs
+#[cfg(unix)]
+struct Registered(libc::pid_t);
+
+#[cfg(unix)]
+impl Registered {
+    fn new(pid: libc::pid_t) -> Self {
+        CHILDREN.lock().expect("children lock poisoned").insert(pid);
+        Self(pid)
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Registered {
+    fn drop(&mut self) {
+        CHILDREN
+            .lock()
+            .expect("children lock poisoned")
+            .remove(&self.0);
+    }
+}
+
+/// Makes a child reachable by pause and resume while it runs. The child sends its pid over
+/// a socket between fork and exec, since libffmpeg spawns encodes itself and never hands
+/// the pid out
+#[cfg(unix)]
+pub struct ChildTracker {
+    writer: std::os::unix::net::UnixDatagram,
+    reader: tokio::net::UnixDatagram,
+}
+
+/// The child's end of a `ChildTracker`, see `ChildTracker::reporter`
+#[cfg(unix)]
+#[derive(Debug, Clone, Copy)]
+pub struct PidReporter(std::os::fd::RawFd);
+
+#[cfg(unix)]
+impl ChildTracker {
+    pub fn new() -> std::io::Result<Self> {
+        let (reader, writer) = std::os::unix::net::UnixDatagram::pair()?;
+        reader.set_nonblocking(true)?;
+        Ok(Self {
+            writer,
+            reader: tokio::net::UnixDatagram::from_std(reader)?,
+        })
+    }
+
+    /// Applied to the `Command` the tracked child is spawned from
+    pub fn reporter(&self) -> PidReporter {
+        use std::os::fd::AsRawFd;
+
+        PidReporter(self.writer.as_raw_fd())
+    }
+
+    /// Runs `fut`, which spawns the child and waits for it, keeping the child registered
+    /// meanwhile
+    pub async fn track<T>(self, fut: impl Future<Output = T>) -> T {
+        let mut registered = None;
+        let mut buf = [0; size_of::<libc::pid_t>()];
+        tokio::pin!(fut);
+        loop {
+            tokio::select! {
+                out = &mut fut => return out,
+                Ok(len) = self.reader.recv(&mut buf), if registered.is_none() => {
+                    if len == buf.len() {
+                        registered = Some(Registered::new(libc::pid_t::from_ne_bytes(buf)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl PidReporter {
+    pub fn apply(self, cmd: &mut tokio::process::Command) {
+        let fd = self.0;
+        // SAFETY: only async-signal-safe syscalls run in the child, and the socket outlives
+        // the spawn, being held by the `ChildTracker`
+        unsafe {
+            cmd.pre_exec(move || {
+                let pid = libc::getpid();
+                libc::send(fd, (&raw const pid).cast(), size_of::<libc::pid_t>(), 0);
+                Ok(())
+            });
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub struct ChildTracker;
+
+#[cfg(not(unix))]
+#[derive(Debug, Clone, Copy)]
+pub struct PidReporter;
+
+#[cfg(not(unix))]
+impl ChildTracker {
+    pub fn new() -> std::io::Result<Self> {
+        Ok(Self)
+    }
+
+    pub fn reporter(&self) -> PidReporter {
+        PidReporter
+    }
+
+    pub async fn track<T>(self, fut: impl Future<Output = T>) -> T {
+        fut.await
+    }
+}
+
+#[cfg(not(unix))]
+impl PidReporter {
+    pub fn apply(self, _cmd: &mut tokio::process::Command) {}
+}
+
+/// Stops children while paused and continues them on resume. Always continues them on
+/// cancellation, so nothing is left stopped behind us
+pub fn spawn_enforcer(control: Arc<PauseControl>, ct: CancellationToken) {
+    let mut rx = control.subscribe();
+    tokio::spawn(async move {
+        loop {
+            let paused = *rx.borrow_and_update();
+            signal_children(paused);
+
+            let changed = async {
+                if paused {
+                    let _ = tokio::time::timeout(STOP_INTERVAL, rx.changed()).await;
+                } else if rx.changed().await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            };
+            tokio::select! {
+                () = changed => {}
+                () = ct.cancelled() => break,
+            }
+        }
+
+        if *rx.borrow() {
+            signal_children(false);
+        }
+    });
+}
+
+/// SIGUSR1 pauses, SIGUSR2 resumes
+#[cfg(unix)]
+pub fn spawn_signal_trigger(control: Arc<PauseControl>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let (Ok(mut pause), Ok(mut resume)) = (
+        signal(SignalKind::user_defined1()),
+        signal(SignalKind::user_defined2()),
+    ) else {
+        tracing::warn!("Failed to listen for SIGUSR1/SIGUSR2, pausing with signals is disabled");
+        return;
+    };
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = pause.recv() => control.set(true),
+                Some(()) = resume.recv() => control.set(false),
+                else => break,
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn_signal_trigger(_control: Arc<PauseControl>) {}
+
+/// Restores the terminal's line buffering and echo when dropped
+pub struct TerminalGuard;
+
+fn stty(args: &[&str]) -> bool {
+    std::process::Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|s| s.success())
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        stty(&["icanon", "echo"]);
+    }
+}
+
+/// Toggles pause when 'p' is pressed. Only when stdin is a terminal; it may be the input
+/// list otherwise
+pub fn spawn_keypress_trigger(control: Arc<PauseControl>) -> Option<TerminalGuard> {
+    if !std::io::stdin().is_terminal() || !stty(&["-icanon", "-echo", "min", "1"]) {
+        return None;
+    }
+
+    // A plain thread, a blocking stdin read would hold up runtime shutdown
+    std::thread::spawn(move || {
+        let mut stdin = std::io::stdin().lock();
+        let mut key = [0; 1];
+        while let Ok(1) = stdin.read(&mut key) {
+            if key[0].eq_ignore_ascii_case(&b'p') {
+                control.toggle();
+            }
+        }
+    });
+
+    Some(TerminalGuard)
+}
+
+/// Accepts "pause", "resume" and "toggle" lines on a unix socket, answering "ok" or an error
+#[cfg(unix)]
+pub fn spawn_control_socket(
+    control: Arc<PauseControl>,
+    path: PathBuf,
+    ct: CancellationToken,
+) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    use anyhow::Context;
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::UnixListener,
+    };
+
+    // Left behind by a previous run, anything else at the path is the user's
+    if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!(
+                "Control socket path \"{}\" exists and is not a socket",
+                path.display()
+            );
+        }
+        std::fs::remove_file(&path).with_context(|| {
+            format!(
+                "Failed to remove stale control socket \"{}\"",
+                path.display()
+            )
+        })?;
+    }
+    let listener = UnixListener::bind(&path)
+        .with_context(|| format!("Failed to bind control socket \"{}\"", path.display()))?;
+
+    tokio::spawn(async move {
+        loop {
+            let stream = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::warn!("Control socket accept failed: {e}");
+                        continue;
+                    }
+                },
+                () = ct.cancelled() => break,
+            };
+
+            let control = control.clone();
+            tokio::spawn(async move {
+                let (read, mut write) = stream.into_split();
+                let mut lines = BufReader::new(read).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let reply = match line.trim() {
+                        "pause" => {
+                            control.set(true);
+                            "ok\n"
+                        }
+                        "resume" => {
+                            control.set(false);
+                            "ok\n"
+                        }
+                        "toggle" => {
+                            control.toggle();
+                            "ok\n"
+                        }
+                        _ => "error: expected pause, resume or toggle\n",
+                    };
+                    if write.write_all(reply.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        let _ = std::fs::remove_file(&path);
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn spawn_control_socket(
+    _control: Arc<PauseControl>,
+    _path: PathBuf,
+    _ct: CancellationToken,
+) -> anyhow::Result<()> {
+    anyhow::bail!("--control-socket is only supported on unix")
+}
@@ -0,0 +1,247 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use crate::{Mp4Mode, analyze, fingerprint, verify::Expectations};
+
+/// CPU tonemap from PQ/HLG to BT.709 SDR, linearizing before applying hable
+pub const TONEMAP_FILTER: &str = "zscale=t=linear:npl=100,format=gbrpf32le,zscale=p=bt709,tonemap=tonemap=hable:desat=0,zscale=t=bt709:m=bt709:r=tv,format=yuv420p";
+/// Whether the local ffmpeg has `zscale` (libzimg), which `TONEMAP_FILTER` needs. Checked
+/// once per run
+pub async fn zscale_available() -> bool {
+    static AVAILABLE: tokio::sync::OnceCell<bool> = tokio::sync::OnceCell::const_new();
+    *AVAILABLE
+        .get_or_init(|| analyze::filter_available("zscale"))
+        .await
+}
+
+/// Converts full range to limited range before dropping to 8-bit 4:2:0
+pub const COMPAT_FILTER: &str = "scale=out_range=tv,format=yuv420p";
+pub const TONEMAP_COLOR_ARGS: [&str; 8] = [
+    "-color_primaries",
+    "bt709",
+    "-color_trc",
+    "bt709",
+    "-colorspace",
+    "bt709",
+    "-color_range",
+    "tv",
+];
+
+/// Names of the `EncodePlan::fallback` levels, indexed by level
+pub const FALLBACKS: [&str; 3] = [
+    "none",
+    "audio transcode",
+    "audio transcode, ignore decode errors",
+];
+
+/// Everything decided by the analysis passes, consumed when building the ffmpeg command
+#[derive(Debug, Clone)]
+pub struct EncodePlan {
+    /// Written to the output's metadata, see `fingerprint`
+    pub fingerprint: Option<String>,
+    /// Written to the output's metadata along with `fingerprint`, see `fingerprint::source`
+    pub source: Option<PathBuf>,
+    /// ffmpeg's share of the thread budget, see `parallel::threads_per_task`
+    pub threads: Option<usize>,
+    /// Options applied to the input, ahead of "-i"
+    pub input_args: Vec<String>,
+    pub audio_args: Vec<String>,
+    pub video_filters: Vec<String>,
+    /// Encoder options following the video codec, e.g. pixel format and color tags
+    pub video_args: Vec<String>,
+    pub extra_args: Vec<String>,
+    pub expected: Expectations,
+    pub no_video: bool,
+    pub mp4_mode: Mp4Mode,
+    pub crf: f64,
+    pub preset: &'static str,
+    /// Stream copy the video instead of encoding it, ignoring filters and encoder options
+    pub copy_video: bool,
+}
+
+impl EncodePlan {
+    pub fn new(no_video: bool, mp4_mode: Mp4Mode, extra_args: Vec<String>) -> Self {
+        Self {
+            fingerprint: None,
+            source: None,
+            threads: None,
+            input_args: vec![],
+            audio_args: vec![],
+            video_filters: vec![],
+            video_args: vec![],
+            extra_args,
+            expected: Expectations::default(),
+            no_video,
+            mp4_mode,
+            crf: 18.0,
+            preset: "ultrafast",
+            copy_video: false,
+        }
+    }
+
+    /// Straight remux of the input's streams, keeping whichever the plan keeps
+    pub fn stream_copy(
+        &self,
+        input_video_codec: Option<String>,
+        input_audio_codec: Option<String>,
+    ) -> Self {
+        let mut plan = Self::new(self.no_video, self.mp4_mode, self.extra_args.clone());
+        plan.copy_video = true;
+        plan.fingerprint.clone_from(&self.fingerprint);
+        plan.source.clone_from(&self.source);
+        plan.threads = self.threads;
+        plan.expected = Expectations {
+            duration: self.expected.duration,
+            video_codec: self.expected.video_codec.as_ref().and(input_video_codec),
+            audio_codec: self.expected.audio_codec.as_ref().and(input_audio_codec),
+        };
+        if self.expected.audio_codec.is_some() {
+            plan.audio_args.extend(["-c:a", "copy"].map(String::from));
+        } else {
+            plan.audio_args.push("-an".to_string());
+        }
+        plan
+    }
+
+    /// A progressively more forgiving variant of the plan for retries, see `FALLBACKS`
+    pub fn fallback(&self, level: usize) -> Self {
+        let mut plan = self.clone();
+        if level >= 1 && plan.audio_args == ["-c:a", "copy"] {
+            // Some sources carry audio mp4 can't hold, or that breaks the muxer
+            plan.audio_args = ["-c:a", "aac", "-b:a", "192k"].map(String::from).into();
+            plan.expected.audio_codec = Some("aac".to_string());
+        }
+        if level >= 2 {
+            plan.input_args
+                .extend(["-err_detect", "ignore_err"].map(String::from));
+        }
+        plan
+    }
+
+    /// The full argument list passed to `FFmpeg`
+    pub fn ffmpeg_args(&self, input: &Path, output: &Path) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![];
+
+        // Add input
+        args.push("-y".into());
+        args.extend(self.input_args.iter().map(OsString::from));
+        args.push("-i".into());
+        args.push(input.into());
+
+        args.extend(self.audio_args.iter().map(OsString::from));
+
+        if self.no_video {
+            // Remove video
+            args.push("-vn".into());
+        } else if self.copy_video {
+            args.extend(["-c:v", "copy"].map(OsString::from));
+        } else {
+            if !self.video_filters.is_empty() {
+                args.push("-vf".into());
+                args.push(self.video_filters.join(",").into());
+            }
+            // Remux to x264
+            args.extend(["-c:v", "libx264"].map(OsString::from));
+            args.push("-crf".into());
+            args.push(self.crf.to_string().into());
+            args.push("-preset".into());
+            args.push(self.preset.into());
+            args.extend(self.video_args.iter().map(OsString::from));
+            if let Some(threads) = self.threads {
+                // -threads sets libx264's frame threads, filters have their own pool
+                args.push("-threads".into());
+                args.push(threads.to_string().into());
+                args.push("-filter_threads".into());
+                args.push(threads.to_string().into());
+            }
+        }
+
+        self.push_container_args(&mut args);
+
+        args.extend(self.extra_args.iter().map(OsString::from));
+
+        args.push(output.into());
+        args
+    }
+
+    fn push_container_args(&self, args: &mut Vec<OsString>) {
+        // mov
+        let mut movflags = self.mp4_mode.movflags().unwrap_or_default().to_string();
+        if let Some(fingerprint) = &self.fingerprint {
+            args.push("-metadata".into());
+            args.push(format!("{}={fingerprint}", fingerprint::TAG).into());
+            if let Some(source) = &self.source {
+                args.push("-metadata".into());
+                let mut tag = OsString::from(format!("{}=", fingerprint::SOURCE_TAG));
+                tag.push(source);
+                args.push(tag);
+            }
+            // mp4 drops keys it doesn't know without this
+            movflags.push_str("+use_metadata_tags");
+        }
+        if !movflags.is_empty() {
+            args.push("-movflags".into());
+            args.push(movflags.into());
+        }
+        // mp4
+        args.extend(["-f", "mp4"].map(OsString::from));
+    }
+
+    /// Video only encode of the input from `start`, for `length` or to the end. Audio is
+    /// handled once over the whole input when the segments are joined, see `concat_args`
+    pub fn segment(&self, start: Duration, length: Option<Duration>) -> Self {
+        let mut plan = self.clone();
+        plan.audio_args = vec!["-an".to_string()];
+        plan.fingerprint = None;
+        plan.source = None;
+        plan.mp4_mode = Mp4Mode::Standard;
+        plan.input_args.push("-ss".to_string());
+        plan.input_args.push(format!("{:.6}", start.as_secs_f64()));
+        if let Some(length) = length {
+            plan.input_args.push("-t".to_string());
+            plan.input_args.push(format!("{:.6}", length.as_secs_f64()));
+        }
+        plan
+    }
+
+    /// Joins encoded segments listed in the concat demuxer file `list` without re-encoding
+    /// them, taking the audio from `input` with the plan's audio settings
+    pub fn concat_args(&self, list: &Path, input: &Path, output: &Path) -> Vec<OsString> {
+        let mut args: Vec<OsString> = vec![];
+
+        args.extend(["-y", "-f", "concat", "-safe", "0", "-i"].map(OsString::from));
+        args.push(list.into());
+        args.push("-i".into());
+        args.push(input.into());
+        args.extend(["-map", "0:v:0", "-map", "1:a:0?", "-c:v", "copy"].map(OsString::from));
+        args.extend(self.audio_args.iter().map(OsString::from));
+
+        self.push_container_args(&mut args);
+
+        args.push(output.into());
+        args
+    }
+}
+
+/// Splits a user supplied `-vf`/`-filter:v` out of the passthrough args, so it can be
+/// appended to our filter chain instead of silently replacing it
+pub fn split_video_filter(args: &[String]) -> (Option<String>, Vec<String>) {
+    let mut filter = None;
+    let mut rest = Vec::with_capacity(args.len());
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if matches!(arg.as_str(), "-vf" | "-filter:v")
+            && let Some(value) = args.next()
+        {
+            filter = Some(value.clone());
+            continue;
+        }
+        rest.push(arg.clone());
+    }
+
+    (filter, rest)
+}
@@ -0,0 +1,90 @@
+use std::{sync::OnceLock, time::Duration};
+
+use clap::ValueEnum;
+use valuable::Valuable;
+
+/// How often the load average is checked while waiting for it to drop
+pub const LOAD_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// `--nice` and `--ionice`, see `set`
+static PRIORITY: OnceLock<(Option<i32>, Option<IoPriority>)> = OnceLock::new();
+
+/// `--ionice` scheduling class
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Valuable)]
+pub enum IoPriority {
+    /// Only get disk time when nothing else wants it
+    Idle,
+    /// Lowest best-effort priority
+    Low,
+}
+
+impl IoPriority {
+    /// `IOPRIO_PRIO_VALUE(class, data)`
+    #[cfg(target_os = "linux")]
+    fn ioprio(self) -> libc::c_int {
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        match self {
+            Self::Idle => 3 << IOPRIO_CLASS_SHIFT,
+            Self::Low => (2 << IOPRIO_CLASS_SHIFT) | 7,
+        }
+    }
+}
+
+/// Sets the CPU and IO priority `apply` gives every ffmpeg and ffprobe we spawn
+pub fn set(nice: Option<i32>, ionice: Option<IoPriority>) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    if nice.is_some_and(|nice| nice < 0) && unsafe { libc::geteuid() } != 0 {
+        anyhow::bail!("A negative --nice needs root");
+    }
+    let _ = PRIORITY.set((nice, ionice));
+    Ok(())
+}
+
+/// Applies the priority given to `set` to `cmd`'s child, between fork and exec. Renicing
+/// ourselves wouldn't reach it: on Linux both only apply to the targeted thread, and the
+/// children are spawned from tokio's worker threads
+#[cfg(unix)]
+pub fn apply(cmd: &mut tokio::process::Command) {
+    let Some(&(nice, ionice)) = PRIORITY.get() else {
+        return;
+    };
+    if nice.is_none() && ionice.is_none() {
+        return;
+    }
+
+    // SAFETY: only async-signal-safe syscalls run in the child
+    unsafe {
+        cmd.pre_exec(move || {
+            // Absolute, unlike `renice -n`
+            if let Some(nice) = nice
+                && libc::setpriority(libc::PRIO_PROCESS, 0, nice) == -1
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            #[cfg(target_os = "linux")]
+            if let Some(ionice) = ionice {
+                const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+                if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ionice.ioprio()) == -1
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            #[cfg(not(target_os = "linux"))]
+            let _ = ionice;
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub fn apply(_cmd: &mut tokio::process::Command) {}
+
+/// 1-minute load average, `None` where /proc/loadavg doesn't exist
+pub fn load_average() -> Option<f64> {
+    std::fs::read_to_string("/proc/loadavg")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
@@ -0,0 +1,149 @@
+use std::{collections::HashMap, path::Path, process::Stdio, time::Duration};
+
+use anyhow::Context;
+use serde::Deserialize;
+use tokio_util::{future::FutureExt, sync::CancellationToken};
+
+use crate::{pause::ChildTracker, priority, shutdown};
+
+/// Subset of `ffprobe -show_streams -show_format` we care about
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Probe {
+    #[serde(default)]
+    pub streams: Vec<ProbeStream>,
+    #[serde(default)]
+    pub format: ProbeFormat,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProbeFormat {
+    /// Seconds, as a decimal string
+    pub duration: Option<String>,
+    /// Seconds, as a decimal string. Timestamps in the input are offset by this
+    pub start_time: Option<String>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct ProbeStream {
+    pub codec_type: Option<String>,
+    pub codec_name: Option<String>,
+    pub profile: Option<String>,
+    pub level: Option<i32>,
+    pub pix_fmt: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Rational, e.g. "30000/1001"
+    pub avg_frame_rate: Option<String>,
+    pub channels: Option<u32>,
+    pub color_range: Option<String>,
+    pub color_space: Option<String>,
+    pub color_transfer: Option<String>,
+    pub color_primaries: Option<String>,
+}
+
+impl Probe {
+    pub fn streams_of(&self, codec_type: &str) -> Vec<&ProbeStream> {
+        self.streams
+            .iter()
+            .filter(|s| s.codec_type.as_deref() == Some(codec_type))
+            .collect()
+    }
+
+    pub fn video(&self) -> Option<&ProbeStream> {
+        self.streams_of("video").into_iter().next()
+    }
+
+    pub fn audio(&self) -> Option<&ProbeStream> {
+        self.streams_of("audio").into_iter().next()
+    }
+
+    pub fn duration(&self) -> Option<Duration> {
+        let seconds = self.format.duration.as_deref()?.parse::<f64>().ok()?;
+        Duration::try_from_secs_f64(seconds).ok()
+    }
+
+    /// Zero when missing, or negative as some edit lists make it
+    pub fn start_time(&self) -> Duration {
+        self.format
+            .start_time
+            .as_deref()
+            .and_then(|s| s.parse::<f64>().ok())
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            .unwrap_or_default()
+    }
+}
+
+impl ProbeStream {
+    pub fn frame_rate(&self) -> Option<f64> {
+        let (num, den) = self.avg_frame_rate.as_deref()?.split_once('/')?;
+        let (num, den) = (num.parse::<f64>().ok()?, den.parse::<f64>().ok()?);
+        (den > 0.0).then(|| num / den)
+    }
+
+    /// PQ (HDR10/Dolby Vision) or HLG transfer
+    pub fn is_hdr(&self) -> bool {
+        matches!(
+            self.color_transfer.as_deref(),
+            Some("smpte2084" | "arib-std-b67")
+        )
+    }
+
+    /// Encoder arguments that carry the source color description through to the output.
+    /// `range` replaces the source range when the filter chain converts it
+    pub fn color_args(&self, range: Option<&str>) -> Vec<String> {
+        let mut args = vec![];
+        let tags = [
+            ("-color_primaries", self.color_primaries.as_deref()),
+            ("-color_trc", self.color_transfer.as_deref()),
+            ("-colorspace", self.color_space.as_deref()),
+            ("-color_range", range.or(self.color_range.as_deref())),
+        ];
+        for (flag, value) in tags {
+            if let Some(value) = value.filter(|v| *v != "unknown") {
+                args.push(flag.to_string());
+                args.push(value.to_string());
+            }
+        }
+        args
+    }
+}
+
+pub async fn probe(input: &Path, ct: CancellationToken) -> anyhow::Result<Probe> {
+    let mut cmd = tokio::process::Command::new("ffprobe");
+    cmd.args([
+        "-v",
+        "error",
+        "-print_format",
+        "json",
+        "-show_streams",
+        "-show_format",
+    ])
+    .arg(input)
+    .stdin(Stdio::null())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .kill_on_drop(true);
+    priority::apply(&mut cmd);
+    shutdown::detach(&mut cmd);
+    let tracker = ChildTracker::new()?;
+    tracker.reporter().apply(&mut cmd);
+    let output = tracker
+        .track(cmd.output())
+        .with_cancellation_token(&ct)
+        .await
+        .context("Cancelled while probing")??;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "ffprobe exited with {} for \"{}\": {}",
+            output.status,
+            input.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Failed to parse ffprobe output for \"{}\"", input.display()))
+}
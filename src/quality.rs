@@ -0,0 +1,105 @@
+use std::{ffi::OsString, path::Path, time::Duration};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+use valuable::Valuable;
+
+use crate::analyze::{self, ffmpeg_stderr};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Valuable)]
+pub enum Metric {
+    Ssim,
+    Psnr,
+    /// Requires an ffmpeg built with libvmaf
+    Vmaf,
+}
+
+impl Metric {
+    fn filter(self) -> &'static str {
+        match self {
+            Self::Ssim => "ssim",
+            Self::Psnr => "psnr",
+            Self::Vmaf => "libvmaf",
+        }
+    }
+
+    /// Pulls the overall score out of the filter's summary line, e.g.
+    /// "SSIM Y:0.995 U:0.993 V:0.994 All:0.994 (22.4)"
+    /// "PSNR y:48.1 u:50.2 v:50.0 average:48.7 min:44.0 max:53.1"
+    /// "VMAF score: 95.42"
+    fn parse(self, stderr: &str) -> Option<f64> {
+        let (line_marker, value_marker) = match self {
+            Self::Ssim => ("SSIM", "All:"),
+            Self::Psnr => ("PSNR", "average:"),
+            Self::Vmaf => ("VMAF score", "score:"),
+        };
+
+        let line = stderr.lines().rev().find(|l| l.contains(line_marker))?;
+        let value = &line[line.find(value_marker)? + value_marker.len()..];
+        value.split_whitespace().next()?.parse().ok()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Valuable)]
+pub struct QualityScores {
+    pub ssim: Option<f64>,
+    pub psnr: Option<f64>,
+    pub vmaf: Option<f64>,
+}
+
+impl QualityScores {
+    pub fn set(&mut self, metric: Metric, score: f64) {
+        match metric {
+            Metric::Ssim => self.ssim = Some(score),
+            Metric::Psnr => self.psnr = Some(score),
+            Metric::Vmaf => self.vmaf = Some(score),
+        }
+    }
+}
+
+/// Whether the local ffmpeg was built with libvmaf
+pub async fn vmaf_available() -> bool {
+    analyze::filter_available("libvmaf").await
+}
+
+/// Compares `distorted` against `reference`, after running the reference through
+/// `reference_filters` so both have the same geometry. `window` limits the reference to a
+/// `(start, length)` slice, for when `distorted` is an encoded sample of it
+pub async fn measure(
+    metric: Metric,
+    distorted: &Path,
+    reference: &Path,
+    reference_filters: &[String],
+    window: Option<(Duration, Duration)>,
+    ct: CancellationToken,
+) -> anyhow::Result<f64> {
+    let mut args: Vec<OsString> = vec!["-i".into(), distorted.into()];
+    if let Some((start, length)) = window {
+        args.push("-ss".into());
+        args.push(format!("{:.3}", start.as_secs_f64()).into());
+        args.push("-t".into());
+        args.push(format!("{:.3}", length.as_secs_f64()).into());
+    }
+    args.push("-i".into());
+    args.push(reference.into());
+
+    let reference_chain = if reference_filters.is_empty() {
+        "null".to_string()
+    } else {
+        reference_filters.join(",")
+    };
+    let graph = format!(
+        "[0:v]settb=AVTB,setpts=PTS-STARTPTS[dist];[1:v]{reference_chain},settb=AVTB,setpts=PTS-STARTPTS[ref];[dist][ref]{}",
+        metric.filter()
+    );
+    args.push("-lavfi".into());
+    args.push(graph.into());
+    args.extend(["-an", "-f", "null", "-"].map(OsString::from));
+
+    let stderr = ffmpeg_stderr(args, ct).await?;
+    metric
+        .parse(&stderr)
+        .with_context(|| format!("{metric:?} filter did not report a score"))
+}
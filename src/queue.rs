@@ -0,0 +1,290 @@
+use std::{
+    cmp::Ordering,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use tokio::sync::oneshot;
+use valuable::Valuable;
+
+/// `--order`, which waiting task gets the next free slot
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Valuable)]
+pub enum Order {
+    /// The order inputs were given in
+    Input,
+    /// Shortest duration first, so quick clips don't wait behind long files
+    ShortestFirst,
+    /// Longest duration first, so the batch doesn't end on one long straggler
+    LongestFirst,
+    SmallestFirst,
+    LargestFirst,
+}
+
+#[derive(Debug)]
+struct Entry {
+    id: usize,
+    /// Unique per push, a task may queue for more than one slot
+    seq: usize,
+    duration: Duration,
+    size: u64,
+    tx: oneshot::Sender<()>,
+}
+
+impl Order {
+    /// `Less` if `a` should run before `b`. Ties go to input order
+    fn compare(self, a: &Entry, b: &Entry) -> Ordering {
+        let by_key = match self {
+            Self::Input => Ordering::Equal,
+            Self::ShortestFirst => a.duration.cmp(&b.duration),
+            Self::LongestFirst => b.duration.cmp(&a.duration),
+            Self::SmallestFirst => a.size.cmp(&b.size),
+            Self::LargestFirst => b.size.cmp(&a.size),
+        };
+        by_key.then(a.id.cmp(&b.id)).then(a.seq.cmp(&b.seq))
+    }
+}
+
+#[derive(Debug)]
+struct QueueState {
+    /// Slots not held by a running task
+    free: usize,
+    /// Until opened nothing is dispatched, so the order covers every task and not just the
+    /// ones probed so far
+    open: bool,
+    waiting: Vec<Entry>,
+    next_seq: usize,
+}
+
+/// Hands out `capacity` running slots to queued tasks in `Order`
+#[derive(Debug)]
+pub struct TaskQueue {
+    order: Order,
+    state: Mutex<QueueState>,
+}
+
+impl TaskQueue {
+    /// Input order opens straight away, since later tasks can't jump ahead of earlier ones
+    pub fn new(capacity: usize, order: Order) -> Self {
+        Self {
+            order,
+            state: Mutex::new(QueueState {
+                free: capacity,
+                open: order == Order::Input,
+                waiting: vec![],
+                next_seq: 0,
+            }),
+        }
+    }
+
+    /// Queues a task, its `Ticket` resolves once it's the task's turn
+    pub fn push(self: &Arc<Self>, id: usize, duration: Duration, size: u64) -> Ticket {
+        let (tx, rx) = oneshot::channel();
+        let mut state = self.state.lock().expect("queue lock poisoned");
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        state.waiting.push(Entry {
+            id,
+            seq,
+            duration,
+            size,
+            tx,
+        });
+        self.dispatch(&mut state);
+
+        Ticket {
+            queue: self.clone(),
+            seq,
+            rx: Some(rx),
+        }
+    }
+
+    /// Starts handing out slots, once every task has been pushed
+    pub fn open(&self) {
+        let mut state = self.state.lock().expect("queue lock poisoned");
+        state.open = true;
+        self.dispatch(&mut state);
+    }
+
+    fn dispatch(&self, state: &mut QueueState) {
+        while state.open && state.free > 0 {
+            let Some(next) = state
+                .waiting
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| self.order.compare(a, b))
+                .map(|(i, _)| i)
+            else {
+                break;
+            };
+
+            let entry = state.waiting.swap_remove(next);
+            // Buffered, the task may not be waiting on it yet
+            if entry.tx.send(()).is_ok() {
+                state.free -= 1;
+            }
+        }
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().expect("queue lock poisoned");
+        state.free += 1;
+        self.dispatch(&mut state);
+    }
+}
+
+/// A task's place in the queue. Dropping it gives up the place, or the slot if it was
+/// already handed out
+#[derive(Debug)]
+pub struct Ticket {
+    queue: Arc<TaskQueue>,
+    seq: usize,
+    rx: Option<oneshot::Receiver<()>>,
+}
+
+impl Ticket {
+    /// Waits for a running slot, held until the returned `Slot` is dropped
+    pub async fn wait(mut self) -> Slot {
+        if let Some(rx) = self.rx.as_mut() {
+            // The sender lives in the queue until it's used
+            let _ = rx.await;
+        }
+        self.rx = None;
+        Slot {
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        // Turned into a `Slot`
+        if self.rx.is_none() {
+            return;
+        }
+
+        let mut state = self.queue.state.lock().expect("queue lock poisoned");
+        if let Some(i) = state.waiting.iter().position(|e| e.seq == self.seq) {
+            state.waiting.swap_remove(i);
+        } else {
+            // Dispatched already, pass the slot on
+            drop(state);
+            self.queue.release();
+        }
+    }
+}
+
+/// A running slot, see `Ticket::wait`
+#[derive(Debug)]
+pub struct Slot {
+    queue: Arc<TaskQueue>,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.queue.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether the ticket's slot has been handed out, consuming the hand-off when it has
+    fn dispatched(ticket: &mut Ticket) -> bool {
+        ticket.rx.as_mut().is_some_and(|rx| rx.try_recv().is_ok())
+    }
+
+    fn push(queue: &Arc<TaskQueue>, id: usize, secs: u64, size: u64) -> Ticket {
+        queue.push(id, Duration::from_secs(secs), size)
+    }
+
+    #[test]
+    fn input_order_dispatches_straight_away() {
+        let queue = Arc::new(TaskQueue::new(1, Order::Input));
+        let mut a = push(&queue, 0, 10, 0);
+        let mut b = push(&queue, 1, 1, 0);
+        assert!(dispatched(&mut a));
+        assert!(!dispatched(&mut b));
+    }
+
+    #[test]
+    fn waits_for_open_then_sorts() {
+        let queue = Arc::new(TaskQueue::new(1, Order::ShortestFirst));
+        let mut long = push(&queue, 0, 10, 0);
+        let mut short = push(&queue, 1, 1, 0);
+        assert!(!dispatched(&mut long));
+        assert!(!dispatched(&mut short));
+
+        queue.open();
+        assert!(dispatched(&mut short));
+        assert!(!dispatched(&mut long));
+    }
+
+    #[test]
+    fn ties_go_to_input_order() {
+        let queue = Arc::new(TaskQueue::new(1, Order::LargestFirst));
+        let mut second = push(&queue, 1, 1, 100);
+        let mut first = push(&queue, 0, 1, 100);
+        let mut small = push(&queue, 2, 1, 10);
+        queue.open();
+        assert!(dispatched(&mut first));
+        assert!(!dispatched(&mut second));
+        assert!(!dispatched(&mut small));
+    }
+
+    #[test]
+    fn dropping_a_dispatched_ticket_passes_the_slot_on() {
+        let queue = Arc::new(TaskQueue::new(1, Order::SmallestFirst));
+        let mut a = push(&queue, 0, 1, 1);
+        let mut b = push(&queue, 1, 1, 2);
+        queue.open();
+        assert!(dispatched(&mut a));
+        assert!(!dispatched(&mut b));
+
+        drop(a);
+        assert!(dispatched(&mut b));
+    }
+
+    #[test]
+    fn dropping_a_waiting_ticket_gives_up_its_place() {
+        let queue = Arc::new(TaskQueue::new(1, Order::Input));
+        let mut a = push(&queue, 0, 1, 0);
+        let b = push(&queue, 1, 1, 0);
+        let mut c = push(&queue, 2, 1, 0);
+        assert!(dispatched(&mut a));
+
+        drop(b);
+        assert!(!dispatched(&mut c));
+        drop(a);
+        assert!(dispatched(&mut c));
+        assert_eq!(queue.state.lock().unwrap().free, 0);
+    }
+
+    #[test]
+    fn one_task_can_hold_several_tickets() {
+        let queue = Arc::new(TaskQueue::new(2, Order::Input));
+        let mut a = push(&queue, 0, 1, 0);
+        let mut b = push(&queue, 0, 1, 0);
+        let mut c = push(&queue, 1, 1, 0);
+        assert!(dispatched(&mut a));
+        assert!(dispatched(&mut b));
+
+        // Drops the second entry for task 0, not the first
+        drop(b);
+        assert!(dispatched(&mut c));
+        drop(a);
+        assert_eq!(queue.state.lock().unwrap().free, 1);
+    }
+
+    #[tokio::test]
+    async fn dropping_a_slot_releases_it() {
+        let queue = Arc::new(TaskQueue::new(1, Order::Input));
+        let slot = push(&queue, 0, 1, 0).wait().await;
+        let mut next = push(&queue, 1, 1, 0);
+        assert!(!dispatched(&mut next));
+
+        drop(slot);
+        assert!(dispatched(&mut next));
+    }
+}
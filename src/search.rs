@@ -0,0 +1,207 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+use valuable::Valuable;
+
+use crate::{
+    analyze::ffmpeg_stderr,
+    plan::EncodePlan,
+    quality::{self, Metric},
+};
+
+/// `--target-quality`, e.g. "ssim:0.98" or "vmaf:95"
+#[derive(Debug, Clone, Copy, Valuable)]
+pub struct QualityGoal {
+    pub metric: Metric,
+    pub score: f64,
+}
+
+impl FromStr for QualityGoal {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (metric, score) = s
+            .split_once(':')
+            .ok_or_else(|| format!("expected <metric>:<score>, got \"{s}\""))?;
+        Ok(Self {
+            metric: Metric::from_str(metric, true)?,
+            score: score
+                .parse()
+                .map_err(|e| format!("invalid score \"{score}\": {e}"))?,
+        })
+    }
+}
+
+/// Result of a CRF search for one task
+#[derive(Debug, Clone, Copy, Serialize, Valuable)]
+pub struct CrfChoice {
+    pub crf: f64,
+    /// Score the samples are expected to hit at `crf`
+    pub predicted_score: f64,
+    /// Video size extrapolated from the samples over the whole input, in bytes
+    pub predicted_size: u64,
+}
+
+const SEARCH_CRFS: [f64; 4] = [16.0, 20.0, 24.0, 28.0];
+const SAMPLE_POINTS: [f64; 3] = [0.2, 0.5, 0.8];
+pub const SAMPLE_LENGTH: Duration = Duration::from_secs(4);
+
+/// Sample offsets spread across the input, or a single one covering short inputs
+pub fn sample_offsets(total_duration: Duration) -> Vec<Duration> {
+    if total_duration < SAMPLE_LENGTH * SAMPLE_POINTS.len() as u32 * 2 {
+        vec![Duration::ZERO]
+    } else {
+        SAMPLE_POINTS
+            .iter()
+            .map(|p| total_duration.mul_f64(*p))
+            .collect()
+    }
+}
+
+/// Scratch file for an encoded sample, unique to this process and task
+pub fn sample_path(task_id: usize, name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "ffrenc-{}-{task_id}-{name}.mp4",
+        std::process::id()
+    ))
+}
+
+/// Encodes a `SAMPLE_LENGTH` slice of the input starting at `start` with the plan's
+/// settings, returning the size of the encoded sample in bytes
+pub async fn encode_sample(
+    plan: &EncodePlan,
+    input: &Path,
+    output: &Path,
+    start: Duration,
+    with_audio: bool,
+    ct: CancellationToken,
+) -> anyhow::Result<u64> {
+    let mut args = if with_audio {
+        plan.ffmpeg_args(input, output)
+    } else {
+        EncodePlan {
+            audio_args: vec!["-an".to_string()],
+            ..plan.clone()
+        }
+        .ffmpeg_args(input, output)
+    };
+    // Seek on the input side, `ffmpeg_args` always starts with "-y", then the input options
+    // and "-i <input>"
+    args.splice(
+        1..1,
+        [
+            OsString::from("-ss"),
+            format!("{:.3}", start.as_secs_f64()).into(),
+            "-t".into(),
+            format!("{:.3}", SAMPLE_LENGTH.as_secs_f64()).into(),
+        ],
+    );
+
+    ffmpeg_stderr(args, ct).await?;
+    Ok(tokio::fs::metadata(output).await?.len())
+}
+
+/// Scores a sample encoded by `encode_sample` against the same slice of the input
+async fn score_sample(
+    plan: &EncodePlan,
+    metric: Metric,
+    input: &Path,
+    sample: &Path,
+    start: Duration,
+    ct: CancellationToken,
+) -> anyhow::Result<f64> {
+    quality::measure(
+        metric,
+        sample,
+        input,
+        &plan.video_filters,
+        Some((start, SAMPLE_LENGTH)),
+        ct,
+    )
+    .await
+}
+
+/// Encodes and scores samples at a spread of CRF values, then interpolates the highest CRF
+/// (smallest output) that still meets the goal
+pub async fn search_crf(
+    plan: &EncodePlan,
+    goal: QualityGoal,
+    input: &Path,
+    task_id: usize,
+    total_duration: Duration,
+    ct: CancellationToken,
+) -> anyhow::Result<CrfChoice> {
+    let offsets = sample_offsets(total_duration);
+    let sampled = SAMPLE_LENGTH.min(total_duration) * offsets.len() as u32;
+
+    // (crf, mean score, bytes per sampled second)
+    let mut points: Vec<(f64, f64, f64)> = Vec::with_capacity(SEARCH_CRFS.len());
+    for crf in SEARCH_CRFS {
+        let candidate = EncodePlan {
+            crf,
+            ..plan.clone()
+        };
+
+        let mut score = 0.0;
+        let mut bytes = 0;
+        for (i, start) in offsets.iter().enumerate() {
+            let sample = sample_path(task_id, &format!("crf{crf}-{i}"));
+            let result = async {
+                bytes += encode_sample(&candidate, input, &sample, *start, false, ct.child_token())
+                    .await?;
+                score_sample(plan, goal.metric, input, &sample, *start, ct.child_token()).await
+            }
+            .await;
+            let _ = tokio::fs::remove_file(&sample).await;
+            score += result.with_context(|| format!("Failed to score sample at crf {crf}"))?;
+        }
+
+        let score = score / offsets.len() as f64;
+        tracing::debug!(task_id, crf, score, bytes, "Scored crf samples");
+        points.push((
+            crf,
+            score,
+            bytes as f64 / sampled.as_secs_f64().max(f64::EPSILON),
+        ));
+    }
+
+    let total = total_duration.as_secs_f64();
+    let choice = |(crf, score, rate): (f64, f64, f64)| CrfChoice {
+        crf,
+        predicted_score: score,
+        predicted_size: (rate * total) as u64,
+    };
+
+    // Scores fall as crf rises, find the pair straddling the goal
+    let Some(passing) = points
+        .iter()
+        .rposition(|(_, score, _)| *score >= goal.score)
+    else {
+        tracing::warn!(task_id, "No crf met the quality goal, using the lowest");
+        return Ok(choice(points[0]));
+    };
+    let Some(&failing) = points.get(passing + 1) else {
+        return Ok(choice(points[passing]));
+    };
+    let passing = points[passing];
+
+    let t = ((passing.1 - goal.score) / (passing.1 - failing.1)).clamp(0.0, 1.0);
+    let lerp = |a: f64, b: f64| a + (b - a) * t;
+    // Size scales roughly exponentially with crf
+    let rate = lerp(passing.2.ln(), failing.2.ln()).exp();
+
+    Ok(choice((
+        // x264 takes fractional crf, two decimals is plenty
+        (lerp(passing.0, failing.0) * 100.0).round() / 100.0,
+        lerp(passing.1, failing.1),
+        rate,
+    )))
+}
@@ -0,0 +1,90 @@
+use tokio_util::sync::CancellationToken;
+
+/// SIGTERM, or SIGHUP from the terminal or SSH session going away
+#[cfg(unix)]
+async fn terminated() {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    async fn received(kind: SignalKind) {
+        match signal(kind) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(_) => std::future::pending().await,
+        }
+    }
+
+    tokio::select! {
+        () = received(SignalKind::terminate()) => {}
+        () = received(SignalKind::hangup()) => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminated() {
+    std::future::pending().await;
+}
+
+/// Moves `cmd`'s child into its own process group, out of the terminal's foreground one, so
+/// Ctrl-C only reaches us and `handle_signals` decides what stops. Children must not read
+/// the terminal, a background group doing so gets stopped.
+///
+/// On Linux the child is also killed if we die without cleaning up, e.g. to SIGKILL. Strictly
+/// that's when the spawning thread exits, tokio's workers live as long as the runtime
+pub fn detach(cmd: &mut tokio::process::Command) {
+    #[cfg(unix)]
+    cmd.process_group(0);
+    #[cfg(not(unix))]
+    let _ = cmd;
+
+    #[cfg(target_os = "linux")]
+    {
+        let parent = std::process::id();
+        // SAFETY: only async-signal-safe syscalls run in the child
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL as libc::c_ulong) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                // Died before the prctl took effect
+                if libc::getppid() as u32 != parent {
+                    return Err(std::io::Error::from_raw_os_error(libc::ESRCH));
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// Two-stage Ctrl-C. The first cancels `drain` so no new tasks start while running ones
+/// finish, the second cancels `abort`, stopping the running ones as well. SIGTERM and SIGHUP
+/// skip straight to the second stage
+pub fn handle_signals(drain: CancellationToken, abort: CancellationToken) {
+    tokio::spawn(async move {
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                if result.is_err() {
+                    return;
+                }
+            }
+            () = terminated() => {
+                drain.cancel();
+                abort.cancel();
+                return;
+            }
+        }
+        eprintln!("\nFinishing running tasks, press Ctrl-C again to cancel them");
+        drain.cancel();
+
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                if result.is_err() {
+                    return;
+                }
+            }
+            () = terminated() => {}
+        }
+        eprintln!("\nCancelling running tasks");
+        abort.cancel();
+    });
+}
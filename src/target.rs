@@ -0,0 +1,197 @@
+use clap::ValueEnum;
+use valuable::Valuable;
+
+use crate::{Mp4Mode, probe::Probe};
+
+/// Playback targets, each constraining the output to what those devices reliably play
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Valuable)]
+pub enum Target {
+    /// iPhone/iPad photos app and `AirDrop`
+    Ios,
+    /// HTML5 video in current browsers
+    Web,
+    /// Smart TVs and streaming sticks via USB/DLNA
+    Tv,
+    /// Discord inline embeds
+    Discord,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TargetProfile {
+    /// Highest profile allowed, as reported by ffprobe, e.g. "High"
+    pub profile: &'static str,
+    /// x264 `-profile:v` value
+    pub x264_profile: &'static str,
+    /// H.264 level times ten, e.g. 41 for 4.1
+    pub level: i32,
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_fps: u32,
+    pub pix_fmt: &'static str,
+    pub audio_codec: &'static str,
+    pub audio_bitrate: &'static str,
+    pub max_audio_channels: u32,
+    pub mp4_mode: Mp4Mode,
+}
+
+impl Target {
+    pub fn profile(self) -> TargetProfile {
+        let base = TargetProfile {
+            profile: "High",
+            x264_profile: "high",
+            level: 41,
+            max_width: 1920,
+            max_height: 1080,
+            max_fps: 60,
+            pix_fmt: "yuv420p",
+            audio_codec: "aac",
+            audio_bitrate: "160k",
+            max_audio_channels: 2,
+            mp4_mode: Mp4Mode::Faststart,
+        };
+
+        match self {
+            // 1080p60 needs 4.2
+            Self::Ios | Self::Web => TargetProfile { level: 42, ..base },
+            Self::Tv => TargetProfile {
+                max_fps: 30,
+                audio_bitrate: "384k",
+                max_audio_channels: 6,
+                ..base
+            },
+            Self::Discord => TargetProfile {
+                profile: "Main",
+                x264_profile: "main",
+                level: 31,
+                max_width: 1280,
+                max_height: 720,
+                max_fps: 30,
+                audio_bitrate: "128k",
+                ..base
+            },
+        }
+    }
+}
+
+impl TargetProfile {
+    /// Downscales (never upscales) to fit, keeping the aspect ratio and even dimensions
+    pub fn scale_filter(&self) -> String {
+        format!(
+            "scale=w='min({},iw)':h='min({},ih)':force_original_aspect_ratio=decrease:force_divisible_by=2",
+            self.max_width, self.max_height
+        )
+    }
+
+    pub fn video_args(&self) -> Vec<String> {
+        [
+            "-profile:v".to_string(),
+            self.x264_profile.to_string(),
+            "-level:v".to_string(),
+            format!("{}.{}", self.level / 10, self.level % 10),
+            "-pix_fmt".to_string(),
+            self.pix_fmt.to_string(),
+            "-fpsmax".to_string(),
+            self.max_fps.to_string(),
+        ]
+        .into()
+    }
+
+    /// Downmixes (never upmixes) to `max_audio_channels`, given the input's channel count
+    pub fn audio_args(&self, channels: Option<u32>) -> Vec<String> {
+        let mut args = vec![
+            "-c:a".to_string(),
+            self.audio_codec.to_string(),
+            "-b:a".to_string(),
+            self.audio_bitrate.to_string(),
+        ];
+        if channels.is_none_or(|channels| channels > self.max_audio_channels) {
+            args.push("-ac".to_string());
+            args.push(self.max_audio_channels.to_string());
+        }
+        args
+    }
+
+    /// Checks an encoded output against the constraints, returning every violation found
+    pub fn validate(&self, probe: &Probe) -> Vec<String> {
+        let mut violations = vec![];
+
+        if let Some(video) = probe.video() {
+            if video.codec_name.as_deref() != Some("h264") {
+                violations.push(format!(
+                    "video codec is {}, expected h264",
+                    video.codec_name.as_deref().unwrap_or("unknown")
+                ));
+            }
+            // x264 only caps the profile, fast presets come out lower than asked for
+            let allowed = profile_rank(self.profile);
+            if video
+                .profile
+                .as_deref()
+                .and_then(profile_rank)
+                .is_none_or(|rank| Some(rank) > allowed)
+            {
+                violations.push(format!(
+                    "h264 profile is {}, expected {} or lower",
+                    video.profile.as_deref().unwrap_or("unknown"),
+                    self.profile
+                ));
+            }
+            if let Some(level) = video.level
+                && level > self.level
+            {
+                violations.push(format!("h264 level {level} exceeds {}", self.level));
+            }
+            if video.pix_fmt.as_deref() != Some(self.pix_fmt) {
+                violations.push(format!(
+                    "pixel format is {}, expected {}",
+                    video.pix_fmt.as_deref().unwrap_or("unknown"),
+                    self.pix_fmt
+                ));
+            }
+            if let (Some(width), Some(height)) = (video.width, video.height)
+                && (width > self.max_width || height > self.max_height)
+            {
+                violations.push(format!(
+                    "resolution {width}x{height} exceeds {}x{}",
+                    self.max_width, self.max_height
+                ));
+            }
+            if let Some(fps) = video.frame_rate()
+                && fps > f64::from(self.max_fps) + 0.01
+            {
+                violations.push(format!("frame rate {fps:.2} exceeds {}", self.max_fps));
+            }
+        }
+
+        if let Some(audio) = probe.audio() {
+            if audio.codec_name.as_deref() != Some(self.audio_codec) {
+                violations.push(format!(
+                    "audio codec is {}, expected {}",
+                    audio.codec_name.as_deref().unwrap_or("unknown"),
+                    self.audio_codec
+                ));
+            }
+            if let Some(channels) = audio.channels
+                && channels > self.max_audio_channels
+            {
+                violations.push(format!(
+                    "{channels} audio channels exceeds {}",
+                    self.max_audio_channels
+                ));
+            }
+        }
+
+        violations
+    }
+}
+
+/// Orders the h264 profiles devices commonly support, each a superset of the one before.
+/// `None` for anything beyond High, e.g. "High 10" or "High 4:2:2"
+fn profile_rank(profile: &str) -> Option<u8> {
+    match profile {
+        "Constrained Baseline" | "Baseline" => Some(0),
+        "Main" => Some(1),
+        "High" => Some(2),
+        _ => None,
+    }
+}
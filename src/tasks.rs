@@ -1,33 +1,226 @@
-use std::{path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::VecDeque,
+    ffi::OsString,
+    fmt,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
-use libffmpeg::ffmpeg::ffmpeg_with_progress;
-use tokio::{sync::Semaphore, task::JoinHandle};
+use libffmpeg::{
+    ffmpeg::{FfmpegError, ffmpeg_with_progress},
+    util::cmd::CommandExit,
+};
+use serde::Serialize;
+use tokio::task::JoinHandle;
 use tokio_util::{future::FutureExt, sync::CancellationToken};
 use valuable::Valuable;
 
-use crate::{Args, ui::UiMessage};
+use crate::{
+    Args, Deinterlace, IfLarger, Mp4Mode,
+    analyze::{self, LoudnessTarget, ScanType},
+    chunk,
+    dry_run::DryRunTask,
+    estimate::Estimate,
+    fingerprint::{self, FingerprintCache},
+    journal::{JobState, Journal},
+    parallel,
+    pause::{ChildTracker, PauseControl},
+    plan::{
+        self, COMPAT_FILTER, EncodePlan, FALLBACKS, TONEMAP_COLOR_ARGS, TONEMAP_FILTER,
+        split_video_filter,
+    },
+    priority,
+    probe::{self, Probe, ProbeStream},
+    quality::{self, QualityScores},
+    queue::{TaskQueue, Ticket},
+    search, shutdown,
+    target::Target,
+    ui::{UiMessage, UiMessagePayload},
+    verify::Expectations,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Valuable)]
+pub enum SizeDecision {
+    Kept,
+    Discarded,
+    Copied,
+    Reencoded,
+}
+
+/// Outcome of `--keep-if-smaller`, sizes in bytes
+#[derive(Debug, Clone, Copy, Serialize, Valuable)]
+pub struct SizeReport {
+    pub input_size: u64,
+    /// Size of the output that was kept, or of the discarded one
+    pub output_size: u64,
+    pub decision: SizeDecision,
+}
+
+/// One attempt at encoding a task
+#[derive(Debug, Clone, Serialize, Valuable)]
+pub struct AttemptRecord {
+    /// 1-based
+    pub attempt: usize,
+    pub fallback: &'static str,
+    /// `None` if the attempt succeeded
+    pub error: Option<String>,
+}
+
+/// How far a `--chunks` output's duration may drift from the input's
+const JOIN_TOLERANCE: Duration = Duration::from_millis(500);
+/// How often the encode monitor checks for stalls and timeouts
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskOutcome {
+    Succeeded,
+    Failed,
+    /// Cancelled before or while running, by a signal or `--fail-fast`
+    Cancelled,
+    /// The output was already up to date
+    Skipped,
+}
+
+/// Why a task ended without producing an output
+#[derive(Debug, Clone)]
+pub enum TaskFailure {
+    Ffmpeg(FfmpegError),
+    /// One of the pre-encode analysis passes failed
+    Analysis(String),
+    /// The output was encoded but doesn't meet the `--target` constraints
+    Target(String),
+    /// The output was encoded but doesn't match what was asked for, e.g. truncated or missing a stream
+    Verification(String),
+    /// The output scored below one of the `--min-*` quality thresholds
+    Quality(String),
+    /// ffmpeg made no progress for `--stall-timeout`
+    Stalled(Duration),
+    /// The encode ran past `--task-timeout`
+    TimedOut(Duration),
+}
+
+impl TaskFailure {
+    fn analysis(error: anyhow::Error) -> Self {
+        Self::Analysis(format!("{error:#}"))
+    }
+
+    /// Whether another attempt could plausibly succeed. Thresholds, target constraints and
+    /// encode speed won't change between attempts, stalls on flaky storage might
+    fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::Ffmpeg(_) | Self::Verification(_) | Self::Stalled(_)
+        )
+    }
+}
+
+impl fmt::Display for TaskFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ffmpeg(error) => write!(f, "{error}"),
+            Self::Analysis(error) => write!(f, "Analysis failed: {error}"),
+            Self::Target(violations) => write!(f, "Output violates target: {violations}"),
+            Self::Verification(mismatches) => write!(f, "Output verification failed: {mismatches}"),
+            Self::Quality(below) => write!(f, "Output quality too low: {below}"),
+            Self::Stalled(idle) => write!(f, "Stalled, no progress for {}s", idle.as_secs()),
+            Self::TimedOut(elapsed) => write!(f, "Timed out after {}s", elapsed.as_secs()),
+        }
+    }
+}
 
 #[derive(Debug, Valuable)]
 pub struct SharedTaskContext {
     #[valuable(skip)]
     tx: tokio::sync::mpsc::Sender<UiMessage>,
     #[valuable(skip)]
-    sem: Arc<Semaphore>,
+    queue: Arc<TaskQueue>,
     #[valuable(skip)]
     cancellation_token: CancellationToken,
+    /// Cancelled to stop starting new tasks, without touching running ones
+    #[valuable(skip)]
+    drain_token: CancellationToken,
+    #[valuable(skip)]
+    journal: Option<Arc<Journal>>,
+    #[valuable(skip)]
+    fingerprints: Arc<FingerprintCache>,
+    #[valuable(skip)]
+    pause: Arc<PauseControl>,
 }
 impl SharedTaskContext {
     pub fn new(
         tx: tokio::sync::mpsc::Sender<UiMessage>,
-        capacity: usize,
+        queue: TaskQueue,
         cancellation_token: CancellationToken,
+        drain_token: CancellationToken,
+        journal: Option<Arc<Journal>>,
+        fingerprints: Arc<FingerprintCache>,
+        pause: Arc<PauseControl>,
     ) -> Self {
         Self {
             tx,
-            sem: Arc::new(Semaphore::new(capacity)),
+            queue: Arc::new(queue),
             cancellation_token,
+            drain_token,
+            journal,
+            fingerprints,
+            pause,
         }
     }
+
+    /// Lets queued tasks start, once all of them are enqueued
+    pub fn open_queue(&self) {
+        self.queue.open();
+    }
+
+    /// Reports a task that couldn't be set up, e.g. an input ffprobe can't read, as failed.
+    /// Other tasks may be running by then, so it mustn't take the batch down with it
+    pub async fn fail_unstarted(
+        self: Arc<Self>,
+        id: usize,
+        input: PathBuf,
+        output: PathBuf,
+        error: anyhow::Error,
+        fail_fast: bool,
+    ) -> anyhow::Result<TaskOutcome> {
+        tracing::error!(task_id = id, input = ?input, "Failed to set up task: {error:#}");
+        if let Some(journal) = &self.journal {
+            journal.set_state(id, JobState::Failed).await;
+        }
+
+        let _ = self
+            .tx
+            .send(UiMessage::new(
+                id,
+                UiMessagePayload::Created {
+                    input,
+                    output,
+                    total: Duration::ZERO,
+                },
+            ))
+            .await;
+        let _ = self
+            .tx
+            .send(UiMessage::new(
+                id,
+                UiMessagePayload::Failed {
+                    error: TaskFailure::analysis(error),
+                },
+            ))
+            .await;
+
+        if fail_fast {
+            tracing::warn!(
+                task_id = id,
+                "Task failed, cancelling the rest (--fail-fast)"
+            );
+            self.cancellation_token.cancel();
+        }
+        Ok(TaskOutcome::Failed)
+    }
 }
 
 #[derive(Debug, Valuable)]
@@ -41,6 +234,9 @@ pub struct Task {
     cx: Arc<SharedTaskContext>,
     #[valuable(skip)]
     total_duration: Duration,
+    #[valuable(skip)]
+    probe: Probe,
+    fingerprint: String,
 }
 impl Task {
     pub async fn new(
@@ -50,11 +246,11 @@ impl Task {
         args: Args,
         cx: Arc<SharedTaskContext>,
     ) -> anyhow::Result<Self> {
-        let duration = libffmpeg::duration::get_duration(
-            &input.display().to_string(),
-            cx.cancellation_token.child_token(),
-        )
-        .await?;
+        let probe = probe::probe(&input, cx.cancellation_token.child_token()).await?;
+        let duration = probe.duration().ok_or_else(|| {
+            anyhow::anyhow!("ffprobe reported no duration for {}", input.display())
+        })?;
+        let fingerprint = fingerprint::compute(&input, &args).await?;
 
         Ok(Self {
             id,
@@ -63,13 +259,20 @@ impl Task {
             args,
             cx,
             total_duration: duration,
+            probe,
+            fingerprint,
         })
     }
 
+    /// Forwards progress to the UI as mapped by `report`, and cancels `encode_token` if the
+    /// encode stalls or runs past `--task-timeout`, returning why. Time spent paused counts
+    /// towards neither
     fn spawn_monitor(
         &self,
         mut rx: tokio::sync::mpsc::Receiver<Duration>,
-    ) -> (CancellationToken, JoinHandle<()>) {
+        encode_token: CancellationToken,
+        report: impl Fn(Duration) -> Duration + Send + 'static,
+    ) -> (CancellationToken, JoinHandle<Option<TaskFailure>>) {
         let token = self.cx.cancellation_token.child_token();
 
         let handle = {
@@ -77,23 +280,96 @@ impl Task {
             let tx = self.cx.tx.clone();
             let id = self.id;
             let total = self.total_duration;
+            let pause = self.cx.pause.subscribe();
+            let stall_timeout = self.args.stall_timeout.map(Duration::from_secs);
+            let task_timeout = self.args.task_timeout.map(|f| total.mul_f64(f));
             tokio::spawn(async move {
+                let mut tick = tokio::time::interval(WATCHDOG_INTERVAL);
+                let mut last_tick = Instant::now();
+                let mut since_progress = Duration::ZERO;
+                let mut last_progress = Duration::ZERO;
+                let mut active = Duration::ZERO;
+
                 while !token.is_cancelled() {
-                    let delivery = match rx.recv().with_cancellation_token(&token).await {
-                        Some(Some(delivery)) => delivery,
-                        Some(None) /* closed */ => break,
-                        None /* cancelled */ => break
+                    tokio::select! {
+                        delivery = rx.recv() => {
+                            // Closed once ffmpeg exits
+                            let Some(delivery) = delivery else {
+                                break;
+                            };
+                            // ffmpeg keeps reporting the same position while stuck
+                            if delivery > last_progress {
+                                last_progress = delivery;
+                                since_progress = Duration::ZERO;
+                            }
+                            let _ = tx
+                                .send(UiMessage {
+                                    task_id: id,
+                                    payload: UiMessagePayload::Progress {
+                                        total,
+                                        current: report(delivery),
+                                    },
+                                })
+                                .await;
+                        }
+                        _ = tick.tick() => {}
+                        () = token.cancelled() => break,
+                    }
+
+                    let elapsed = last_tick.elapsed();
+                    last_tick = Instant::now();
+                    if *pause.borrow() {
+                        continue;
+                    }
+                    since_progress += elapsed;
+                    active += elapsed;
+
+                    let failure = if stall_timeout.is_some_and(|t| since_progress > t) {
+                        TaskFailure::Stalled(since_progress)
+                    } else if task_timeout.is_some_and(|t| active > t) {
+                        TaskFailure::TimedOut(active)
+                    } else {
+                        continue;
                     };
+                    tracing::warn!(task_id = id, "Killing encode: {failure}");
+                    encode_token.cancel();
+                    return Some(failure);
+                }
+                None
+            })
+        };
+
+        (token, handle)
+    }
 
-                    let _ = tx
-                        .send(UiMessage {
-                            task_id: id,
-                            payload: crate::ui::UiMessagePayload::Progress {
-                                total,
-                                current: delivery,
-                            },
-                        })
-                        .await;
+    /// Reports pausing and resuming to the UI while the task runs
+    fn spawn_pause_watch(&self) -> (CancellationToken, JoinHandle<()>) {
+        let token = self.cx.cancellation_token.child_token();
+
+        let handle = {
+            let token = token.clone();
+            let tx = self.cx.tx.clone();
+            let id = self.id;
+            let mut rx = self.cx.pause.subscribe();
+            tokio::spawn(async move {
+                let mut paused = false;
+                loop {
+                    let now = *rx.borrow_and_update();
+                    if now != paused {
+                        paused = now;
+                        let payload = if paused {
+                            UiMessagePayload::Paused
+                        } else {
+                            UiMessagePayload::Resumed
+                        };
+                        let _ = tx.send(UiMessage::new(id, payload)).await;
+                    }
+
+                    match rx.changed().with_cancellation_token(&token).await {
+                        Some(Ok(())) => {}
+                        Some(Err(_)) /* closed */ => break,
+                        None /* cancelled */ => break,
+                    }
                 }
             })
         };
@@ -101,102 +377,954 @@ impl Task {
         (token, handle)
     }
 
-    pub async fn run(self) -> anyhow::Result<()> {
-        let _ = self
-            .cx
-            .tx
-            .send(UiMessage::new(
-                self.id,
-                crate::ui::UiMessagePayload::Created {
-                    input: self.input.clone(),
-                    output: self.output.clone(),
-                    total: self.total_duration,
-                },
-            ))
-            .await;
-        let _guard = self.cx.sem.acquire().await?;
-        let _ = self
-            .cx
-            .tx
-            .send(UiMessage::new(
-                self.id,
-                crate::ui::UiMessagePayload::Started,
-            ))
-            .await;
+    async fn send(&self, payload: UiMessagePayload) {
+        let _ = self.cx.tx.send(UiMessage::new(self.id, payload)).await;
+    }
 
-        let (tx, rx) = tokio::sync::mpsc::channel(100);
+    fn loudness_target(&self) -> LoudnessTarget {
+        LoudnessTarget {
+            integrated: self.args.target_lufs,
+            true_peak: self.args.target_true_peak,
+            lra: self.args.target_lra,
+        }
+    }
 
-        let ct = self.cx.cancellation_token.child_token();
-        let input = self.input.clone();
-        let output = self.output.clone();
-        let no_audio = self.args.no_audio;
-        let no_video = self.args.no_video;
-        let extra_args = self.args.ffmpeg_args.clone();
-
-        let fut = ffmpeg_with_progress(tx, ct, move |cmd| {
-            // Add input
-            cmd.arg("-y");
-            cmd.arg("-i").arg(&input);
-
-            if no_audio {
-                // Strip audio
-                cmd.arg("-an");
+    /// Runs the analysis passes the args ask for and collects their results
+    async fn plan(&self) -> Result<EncodePlan, TaskFailure> {
+        let (user_video_filter, extra_args) = split_video_filter(&self.args.ffmpeg_args);
+        // An explicit --mp4-mode wins over the target's
+        let mp4_mode = self
+            .args
+            .mp4_mode
+            .or(self.args.target.map(|t| t.profile().mp4_mode))
+            .unwrap_or(Mp4Mode::Faststart);
+        let mut plan = EncodePlan::new(self.args.no_video, mp4_mode, extra_args);
+        plan.fingerprint = Some(self.fingerprint.clone());
+        plan.source = Some(fingerprint::source(&self.input));
+        plan.threads = parallel::threads_per_task(self.args.threads, self.args.parallel.jobs());
+
+        let target = self.args.target.map(Target::profile);
+        plan.expected.duration = self.total_duration;
+
+        let mut audio_filter = None;
+        if self.args.normalize_audio && self.probe.audio().is_none() {
+            tracing::info!(task_id = self.id, "Input has no audio, not normalizing");
+        } else if self.args.normalize_audio {
+            let loudness_target = self.loudness_target();
+            let measured = analyze::measure_loudness(
+                &self.input,
+                loudness_target,
+                self.cx.cancellation_token.child_token(),
+            )
+            .await
+            .map_err(TaskFailure::analysis)?;
+
+            if let Some(measured) = measured {
+                tracing::info!(
+                    task_id = self.id,
+                    measured = measured.as_value(),
+                    "Measured input loudness"
+                );
+                self.send(UiMessagePayload::Loudness { measured }).await;
+                audio_filter = Some(loudness_target.apply_filter(&measured));
             } else {
-                // Copy audio
-                cmd.arg("-c:a").arg("copy");
+                tracing::info!(task_id = self.id, "Input audio is silent, not normalizing");
             }
+        }
+
+        let input_audio = self.probe.audio();
+        if self.args.no_audio {
+            // Strip audio
+            plan.audio_args.push("-an".to_string());
+        } else if audio_filter.is_none() && target.is_none() {
+            // Copy audio
+            plan.audio_args.extend(["-c:a", "copy"].map(String::from));
+            plan.expected.audio_codec = input_audio.and_then(|a| a.codec_name.clone());
+        } else {
+            if input_audio.is_some() {
+                plan.expected.audio_codec = Some("aac".to_string());
+            }
+            if let Some(audio_filter) = audio_filter {
+                // Normalize, loudnorm resamples to 192kHz so bring it back down
+                plan.audio_args.push("-af".to_string());
+                plan.audio_args.push(audio_filter);
+                plan.audio_args.extend(["-ar", "48000"].map(String::from));
+            }
+            match &target {
+                Some(target) => plan
+                    .audio_args
+                    .extend(target.audio_args(input_audio.and_then(|a| a.channels))),
+                None => plan
+                    .audio_args
+                    .extend(["-c:a", "aac", "-b:a", "192k"].map(String::from)),
+            }
+        }
+
+        if !self.args.no_video && self.probe.video().is_some() {
+            plan.expected.video_codec = Some("h264".to_string());
+        }
+
+        let scan_type = match self.args.deinterlace {
+            Deinterlace::Never => None,
+            Deinterlace::Always => Some(ScanType::Interlaced),
+            Deinterlace::Auto if self.args.no_video => None,
+            Deinterlace::Auto => {
+                let scan_type = analyze::detect_scan_type(
+                    &self.input,
+                    self.total_duration,
+                    self.cx.cancellation_token.child_token(),
+                )
+                .await
+                .map_err(TaskFailure::analysis)?;
+                tracing::info!(
+                    task_id = self.id,
+                    scan_type = scan_type.as_value(),
+                    "Detected scan type"
+                );
+                Some(scan_type)
+            }
+        };
+        plan.video_filters
+            .extend(scan_type.and_then(ScanType::filter).map(String::from));
+
+        if self.args.auto_crop {
+            match analyze::detect_crop(
+                &self.input,
+                self.total_duration,
+                self.cx.cancellation_token.child_token(),
+            )
+            .await
+            .map_err(TaskFailure::analysis)?
+            {
+                Some(crop) => {
+                    tracing::info!(task_id = self.id, crop = crop.as_value(), "Detected crop");
+                    plan.video_filters.push(crop.filter());
+                }
+                None => {
+                    tracing::info!(task_id = self.id, "No stable crop detected, not cropping");
+                }
+            }
+        }
+
+        // User filters go after ours, so any scaling sees the cropped frame
+        plan.video_filters.extend(user_video_filter);
+
+        if let Some(target) = &target {
+            plan.video_filters.push(target.scale_filter());
+            plan.video_args.extend(target.video_args());
+        }
+
+        // Every target is 8-bit 4:2:0
+        let compat = self.args.compat || target.is_some();
+        let video = self.probe.video();
+        let hdr = video.is_some_and(ProbeStream::is_hdr);
+        if self.args.tonemap && !hdr {
+            tracing::info!(task_id = self.id, "Input is not HDR, not tonemapping");
+        }
 
-            if no_video {
-                // Remove video
-                cmd.arg("-vn");
+        if let Some(video) = video {
+            if hdr && (self.args.tonemap || compat) {
+                // --tonemap is checked up front, --compat and --target only matter for HDR
+                if !plan::zscale_available().await {
+                    return Err(TaskFailure::Analysis(
+                        "HDR input needs tonemapping, which requires an ffmpeg built with libzimg (zscale)"
+                            .to_string(),
+                    ));
+                }
+                tracing::info!(task_id = self.id, "Tonemapping HDR input to SDR");
+                plan.video_filters.push(TONEMAP_FILTER.to_string());
+                plan.video_args
+                    .extend(TONEMAP_COLOR_ARGS.into_iter().map(String::from));
+            } else if compat {
+                // 8-bit 4:2:0 limited range, the only thing every device plays
+                plan.video_filters.push(COMPAT_FILTER.to_string());
+                plan.video_args.extend(video.color_args(Some("tv")));
             } else {
-                // Remux to x264
-                cmd.arg("-c:v").arg("libx264");
-                cmd.arg("-crf").arg("18");
-                cmd.arg("-preset").arg("ultrafast");
+                if hdr {
+                    // Keep it HDR, which needs 10 bits
+                    plan.video_args
+                        .extend(["-pix_fmt".to_string(), "yuv420p10le".to_string()]);
+                } else if let Some(pix_fmt) = video.pix_fmt.as_deref()
+                    && !matches!(pix_fmt, "yuv420p" | "yuvj420p")
+                {
+                    tracing::warn!(
+                        task_id = self.id,
+                        pix_fmt,
+                        "Output keeps a pixel format many devices can't play, use --compat to force yuv420p"
+                    );
+                }
+                plan.video_args.extend(video.color_args(None));
             }
+        }
+
+        if let Some(goal) = self.args.target_quality
+            && !plan.no_video
+        {
+            let choice = search::search_crf(
+                &plan,
+                goal,
+                &self.input,
+                self.id,
+                self.total_duration,
+                self.cx.cancellation_token.child_token(),
+            )
+            .await
+            .map_err(TaskFailure::analysis)?;
+
+            tracing::info!(task_id = self.id, choice = choice.as_value(), "Chose crf");
+            self.send(UiMessagePayload::Crf { choice }).await;
+            plan.crf = choice.crf;
+        }
+
+        Ok(plan)
+    }
 
-            // mov
-            cmd.arg("-movflags").arg("+frag_keyframe+empty_moov");
-            // mp4
-            cmd.arg("-f").arg("mp4");
+    async fn remove_output(&self) {
+        match tokio::fs::remove_file(&self.output).await {
+            Ok(()) => tracing::info!(task_id = self.id, output = ?self.output, "Removed output"),
+            Err(e) => tracing::warn!(
+                task_id = self.id,
+                output = ?self.output,
+                "Failed to remove output: {e}"
+            ),
+        }
+    }
 
-            if !extra_args.is_empty() {
-                cmd.args(&extra_args);
+    /// Removes what a cancelled encode left behind. Outputs that weren't written since
+    /// `started_at` are left alone, they're from an earlier run
+    async fn remove_partial_output(&self, started_at: SystemTime) {
+        let written = tokio::fs::metadata(&self.output)
+            .await
+            .and_then(|m| m.modified())
+            .is_ok_and(|modified| modified >= started_at);
+        if written {
+            self.remove_output().await;
+        }
+    }
+
+    /// Probes the finished output and checks it against what was planned, and `--target` if
+    /// one was given
+    async fn verify_output(&self, expected: &Expectations) -> Result<(), TaskFailure> {
+        if self.args.no_verify && self.args.target.is_none() {
+            return Ok(());
+        }
+
+        let probe = probe::probe(&self.output, self.cx.cancellation_token.child_token())
+            .await
+            .map_err(|e| TaskFailure::Verification(format!("{e:#}")))?;
+
+        if !self.args.no_verify {
+            let mismatches = expected.verify(&probe);
+            if !mismatches.is_empty() {
+                tracing::warn!(
+                    task_id = self.id,
+                    mismatches = mismatches.as_value(),
+                    "Output does not match expectations"
+                );
+                return Err(TaskFailure::Verification(mismatches.join(", ")));
+            }
+        }
+
+        if let Some(target) = self.args.target {
+            let violations = target.profile().validate(&probe);
+            if !violations.is_empty() {
+                tracing::warn!(
+                    task_id = self.id,
+                    violations = violations.as_value(),
+                    "Output does not meet target constraints"
+                );
+                return Err(TaskFailure::Target(violations.join(", ")));
             }
+        }
+
+        Ok(())
+    }
 
-            cmd.arg(&output);
+    /// Encodes a fallback plan over the output, keeping it only if it succeeds and verifies
+    async fn encode_fallback(&self, plan: &EncodePlan) -> bool {
+        match self.encode(plan).await {
+            Ok(exit) if exit.exit_code.as_ref().is_some_and(|ec| ec.success) => {
+                match self.verify_output(&plan.expected).await {
+                    Ok(()) => return true,
+                    Err(e) => tracing::warn!(task_id = self.id, "Fallback output is invalid: {e}"),
+                }
+            }
+            Ok(exit) => tracing::warn!(task_id = self.id, exit = ?exit, "Fallback encode failed"),
+            Err(e) => tracing::warn!(task_id = self.id, "Fallback encode failed: {e}"),
+        }
+        false
+    }
+
+    /// Runs the `--measure` comparison passes between the output and its input
+    async fn measure_quality(&self, plan: &EncodePlan) -> Result<(), TaskFailure> {
+        if self.args.measure.is_empty() || plan.no_video {
+            return Ok(());
+        }
+
+        let mut scores = QualityScores::default();
+        for metric in &self.args.measure {
+            let score = quality::measure(
+                *metric,
+                &self.output,
+                &self.input,
+                &plan.video_filters,
+                None,
+                self.cx.cancellation_token.child_token(),
+            )
+            .await
+            .map_err(TaskFailure::analysis)?;
+            scores.set(*metric, score);
+        }
+
+        tracing::info!(
+            task_id = self.id,
+            scores = scores.as_value(),
+            "Measured quality"
+        );
+        self.send(UiMessagePayload::Quality { scores }).await;
+
+        let below: Vec<String> = [
+            ("SSIM", scores.ssim, self.args.min_ssim),
+            ("PSNR", scores.psnr, self.args.min_psnr),
+            ("VMAF", scores.vmaf, self.args.min_vmaf),
+        ]
+        .into_iter()
+        .filter_map(|(name, score, min)| match (score, min) {
+            (Some(score), Some(min)) if score < min => {
+                Some(format!("{name} {score:.4} is below {min}"))
+            }
+            _ => None,
+        })
+        .collect();
+
+        if below.is_empty() {
+            Ok(())
+        } else {
+            Err(TaskFailure::Quality(below.join(", ")))
+        }
+    }
+
+    /// Applies `--keep-if-smaller` to a finished output
+    async fn enforce_size(&self, plan: &EncodePlan) -> Result<(), TaskFailure> {
+        let Some(ratio) = self.args.keep_if_smaller else {
+            return Ok(());
+        };
+
+        let input_size = file_size(&self.input).await?;
+        let limit = (input_size as f64 * ratio) as u64;
+        let mut output_size = file_size(&self.output).await?;
+
+        let decision = if output_size <= limit {
+            SizeDecision::Kept
+        } else {
+            tracing::info!(
+                task_id = self.id,
+                input_size,
+                output_size,
+                action = self.args.if_larger.as_value(),
+                "Output is larger than allowed"
+            );
+            let replacement = match self.args.if_larger {
+                IfLarger::Discard => None,
+                IfLarger::Copy => Some((
+                    SizeDecision::Copied,
+                    plan.stream_copy(
+                        self.probe.video().and_then(|v| v.codec_name.clone()),
+                        self.probe.audio().and_then(|a| a.codec_name.clone()),
+                    ),
+                )),
+                IfLarger::Reencode => Some((
+                    SizeDecision::Reencoded,
+                    EncodePlan {
+                        crf: plan.crf + 2.0,
+                        preset: "slow",
+                        ..plan.clone()
+                    },
+                )),
+            };
+
+            match replacement {
+                Some((decision, plan)) if self.encode_fallback(&plan).await => {
+                    output_size = file_size(&self.output).await?;
+                    // A stream copy is as small as it gets, keep it regardless
+                    if decision == SizeDecision::Copied || output_size <= limit {
+                        decision
+                    } else {
+                        self.remove_output().await;
+                        SizeDecision::Discarded
+                    }
+                }
+                _ => {
+                    self.remove_output().await;
+                    SizeDecision::Discarded
+                }
+            }
+        };
+
+        let report = SizeReport {
+            input_size,
+            output_size,
+            decision,
+        };
+        tracing::info!(task_id = self.id, report = report.as_value(), "Size check");
+        self.send(UiMessagePayload::Size { report }).await;
+
+        Ok(())
+    }
+
+    /// Runs `FFmpeg` for the plan, forwarding its progress to the UI
+    async fn encode(&self, plan: &EncodePlan) -> Result<CommandExit, TaskFailure> {
+        if let Some(chunks) = self.args.chunks
+            && chunks > 1
+            && !plan.copy_video
+            && !plan.no_video
+        {
+            return self.encode_chunked(plan, chunks).await;
+        }
+
+        self.run_ffmpeg(
+            plan.ffmpeg_args(&self.input, &self.output),
+            self.cx.cancellation_token.child_token(),
+            |current| current,
+        )
+        .await
+    }
+
+    /// Runs `FFmpeg` with `args`, forwarding its progress to the UI through `report`
+    async fn run_ffmpeg(
+        &self,
+        args: Vec<OsString>,
+        ct: CancellationToken,
+        report: impl Fn(Duration) -> Duration + Send + 'static,
+    ) -> Result<CommandExit, TaskFailure> {
+        let (tx, rx) = tokio::sync::mpsc::channel(100);
+
+        let tracker = ChildTracker::new().map_err(|e| TaskFailure::analysis(e.into()))?;
+        let reporter = tracker.reporter();
+        let fut = ffmpeg_with_progress(tx, ct.clone(), move |cmd| {
+            // ffmpeg reads commands from stdin otherwise, which stops it once detached
+            cmd.args(&args).stdin(Stdio::null());
+            priority::apply(cmd);
+            shutdown::detach(cmd);
+            reporter.apply(cmd);
         });
 
-        let (monitor_token, handle) = self.spawn_monitor(rx);
+        let (monitor_token, handle) = self.spawn_monitor(rx, ct, report);
 
-        let result = fut.await;
+        let result = tracker.track(fut).await;
         monitor_token.cancel();
-        handle.abort();
-
-        match result {
-            Ok(exit) => {
-                let _ = self
-                    .cx
-                    .tx
-                    .send(UiMessage::new(
-                        self.id,
-                        crate::ui::UiMessagePayload::Finished { exit },
-                    ))
+        if let Ok(Some(failure)) = handle.await {
+            return Err(failure);
+        }
+
+        result.map_err(TaskFailure::Ffmpeg)
+    }
+
+    /// `--chunks`: encodes the video in keyframe aligned segments at once, then joins them
+    /// and the audio without re-encoding
+    async fn encode_chunked(
+        &self,
+        plan: &EncodePlan,
+        chunks: usize,
+    ) -> Result<CommandExit, TaskFailure> {
+        let segments = chunk::split(
+            &self.input,
+            self.total_duration,
+            self.probe.start_time(),
+            chunks,
+            self.cx.cancellation_token.child_token(),
+        )
+        .await
+        .map_err(TaskFailure::analysis)?;
+        if segments.len() < 2 {
+            tracing::info!(
+                task_id = self.id,
+                "Too short to split, encoding in one piece"
+            );
+            return self
+                .run_ffmpeg(
+                    plan.ffmpeg_args(&self.input, &self.output),
+                    self.cx.cancellation_token.child_token(),
+                    |current| current,
+                )
+                .await;
+        }
+        tracing::info!(
+            task_id = self.id,
+            segments = segments.len(),
+            "Encoding in segments"
+        );
+
+        let paths: Vec<PathBuf> = (0..segments.len())
+            .map(|i| chunk::segment_path(&self.output, i))
+            .collect();
+        let result = match self.encode_segments(plan, &segments, &paths).await {
+            Ok(None) => self.join_segments(plan, &paths).await,
+            Ok(Some(exit)) => Ok(exit),
+            Err(error) => Err(error),
+        };
+        for path in &paths {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+        result
+    }
+
+    /// Encodes every segment, with a worker per slot: this task's own, plus extra ones from
+    /// the queue as they free up. Returns the exit of a segment that failed, if any
+    async fn encode_segments(
+        &self,
+        plan: &EncodePlan,
+        segments: &[(Duration, Option<Duration>)],
+        paths: &[PathBuf],
+    ) -> Result<Option<CommandExit>, TaskFailure> {
+        let ct = self.cx.cancellation_token.child_token();
+        // Cancelled once every segment is taken, so idle workers stop waiting for a slot
+        let claimed = CancellationToken::new();
+        let pending = std::sync::Mutex::new((0..segments.len()).collect::<VecDeque<_>>());
+        let progress = Arc::new(std::sync::Mutex::new(vec![Duration::ZERO; segments.len()]));
+        // Queued like the task itself, so the extra slots sort the same way
+        let size = self.input_size().await;
+
+        let (ct, claimed, pending, progress) = (&ct, &claimed, &pending, &progress);
+        let worker = move |worker: usize| async move {
+            let _slot = if worker == 0 {
+                None
+            } else {
+                let ticket = self.cx.queue.push(self.id, self.total_duration, size);
+                tokio::select! {
+                    slot = ticket.wait() => Some(slot),
+                    () = claimed.cancelled() => return Ok(None),
+                    () = ct.cancelled() => return Ok(None),
+                }
+            };
+
+            loop {
+                let next = {
+                    let mut pending = pending.lock().expect("segment queue lock poisoned");
+                    let next = pending.pop_front();
+                    if pending.is_empty() {
+                        claimed.cancel();
+                    }
+                    next
+                };
+                let Some(i) = next else {
+                    return Ok(None);
+                };
+
+                let (start, length) = segments[i];
+                let progress = progress.clone();
+                let report = move |current: Duration| {
+                    let mut progress = progress.lock().expect("progress lock poisoned");
+                    progress[i] = current;
+                    progress.iter().sum()
+                };
+                let result = self
+                    .run_ffmpeg(
+                        plan.segment(start, length)
+                            .ffmpeg_args(&self.input, &paths[i]),
+                        ct.child_token(),
+                        report,
+                    )
                     .await;
+
+                // Another segment failed, or the task was cancelled
+                if ct.is_cancelled() {
+                    return Ok(None);
+                }
+                match result {
+                    Ok(exit) if exit.exit_code.as_ref().is_some_and(|ec| ec.success) => {}
+                    failed => {
+                        ct.cancel();
+                        return failed.map(Some);
+                    }
+                }
             }
-            Err(e) => {
-                let _ = self
-                    .cx
-                    .tx
-                    .send(UiMessage::new(
-                        self.id,
-                        crate::ui::UiMessagePayload::Failed { error: e },
-                    ))
-                    .await;
+        };
+        let results = futures::future::join_all((0..segments.len()).map(worker)).await;
+
+        if self.cx.cancellation_token.is_cancelled() {
+            return Err(TaskFailure::analysis(anyhow::anyhow!(
+                "Cancelled while encoding segments"
+            )));
+        }
+        for result in results {
+            if let Some(exit) = result? {
+                return Ok(Some(exit));
             }
         }
+        Ok(None)
+    }
 
-        Ok(())
+    /// Joins the encoded segments and the input's audio into the output, then checks nothing
+    /// was lost or doubled at the joins
+    async fn join_segments(
+        &self,
+        plan: &EncodePlan,
+        paths: &[PathBuf],
+    ) -> Result<CommandExit, TaskFailure> {
+        let list = chunk::list_path(&self.output);
+        tokio::fs::write(&list, chunk::concat_list(paths))
+            .await
+            .map_err(|e| TaskFailure::analysis(e.into()))?;
+        let result = self
+            .run_ffmpeg(
+                plan.concat_args(&list, &self.input, &self.output),
+                self.cx.cancellation_token.child_token(),
+                |current| current,
+            )
+            .await;
+        let _ = tokio::fs::remove_file(&list).await;
+
+        let exit = result?;
+        if !exit.exit_code.as_ref().is_some_and(|ec| ec.success) {
+            return Ok(exit);
+        }
+
+        let duration = probe::probe(&self.output, self.cx.cancellation_token.child_token())
+            .await
+            .map_err(|e| TaskFailure::Verification(format!("{e:#}")))?
+            .duration()
+            .ok_or_else(|| {
+                TaskFailure::Verification("Joined output has no duration".to_string())
+            })?;
+        let drift = (duration.as_secs_f64() - self.total_duration.as_secs_f64()).abs();
+        if drift > JOIN_TOLERANCE.as_secs_f64() {
+            return Err(TaskFailure::Verification(format!(
+                "Joined segments last {:.3}s, the input {:.3}s",
+                duration.as_secs_f64(),
+                self.total_duration.as_secs_f64()
+            )));
+        }
+
+        Ok(exit)
+    }
+
+    /// Plans the task and returns the command it would run, without running it
+    pub async fn dry_run(self) -> anyhow::Result<DryRunTask> {
+        let plan = self
+            .plan()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to plan {}: {e}", self.input.display()))?;
+
+        let argv = std::iter::once("ffmpeg".to_string())
+            .chain(
+                plan.ffmpeg_args(&self.input, &self.output)
+                    .into_iter()
+                    .map(|a| a.to_string_lossy().into_owned()),
+            )
+            .collect();
+
+        Ok(DryRunTask {
+            id: self.id,
+            input: self.input,
+            output: self.output,
+            argv,
+        })
     }
+
+    /// Encodes a few samples with the planned settings and extrapolates them over the input
+    pub async fn estimate(self) -> anyhow::Result<Estimate> {
+        let plan = self
+            .plan()
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to plan {}: {e}", self.input.display()))?;
+
+        let offsets = search::sample_offsets(self.total_duration);
+        let mut bytes = 0;
+        let mut elapsed = Duration::ZERO;
+        for (i, start) in offsets.iter().enumerate() {
+            let sample = search::sample_path(self.id, &format!("estimate-{i}"));
+            let started = Instant::now();
+            let result = search::encode_sample(
+                &plan,
+                &self.input,
+                &sample,
+                *start,
+                true,
+                self.cx.cancellation_token.child_token(),
+            )
+            .await;
+            elapsed += started.elapsed();
+            let _ = tokio::fs::remove_file(&sample).await;
+            bytes += result?;
+        }
+
+        let sampled = search::SAMPLE_LENGTH.min(self.total_duration) * offsets.len() as u32;
+        let scale = self.total_duration.as_secs_f64() / sampled.as_secs_f64().max(f64::EPSILON);
+
+        Ok(Estimate {
+            id: self.id,
+            input_size: tokio::fs::metadata(&self.input).await?.len(),
+            predicted_size: (bytes as f64 * scale) as u64,
+            predicted_time: elapsed.mul_f64(scale).as_secs_f64(),
+            duration: self.total_duration.as_secs_f64(),
+            input: self.input,
+            output: self.output,
+        })
+    }
+
+    /// Encodes and checks the output for one attempt
+    async fn attempt(&self, plan: &EncodePlan) -> Result<CommandExit, TaskFailure> {
+        let exit = self.encode(plan).await?;
+        if !exit.exit_code.as_ref().is_some_and(|ec| ec.success) {
+            return Ok(exit);
+        }
+
+        let checked = async {
+            self.verify_output(&plan.expected).await?;
+            self.measure_quality(plan).await?;
+            self.enforce_size(plan).await
+        }
+        .await;
+        if let Err(error) = checked {
+            if self.args.remove_invalid {
+                self.remove_output().await;
+            }
+            return Err(error);
+        }
+
+        Ok(exit)
+    }
+
+    /// Plans the task, then attempts it up to `--retries` more times, escalating through the
+    /// fallback plans. Returns the result of the last attempt
+    async fn execute(&self) -> Result<CommandExit, TaskFailure> {
+        let plan = self.plan().await?;
+
+        let mut attempt = 0;
+        loop {
+            let level = if self.args.no_fallback {
+                0
+            } else {
+                attempt.min(FALLBACKS.len() - 1)
+            };
+            let result = self.attempt(&plan.fallback(level)).await;
+
+            let (error, retryable) = match &result {
+                Ok(exit) if exit.exit_code.as_ref().is_some_and(|ec| ec.success) => (None, false),
+                Ok(exit) => (
+                    Some(format!(
+                        "ffmpeg exited unsuccessfully ({:?})",
+                        exit.exit_code
+                    )),
+                    true,
+                ),
+                Err(error) => (Some(error.to_string()), error.is_retryable()),
+            };
+            self.send(UiMessagePayload::Attempt {
+                record: AttemptRecord {
+                    attempt: attempt + 1,
+                    fallback: FALLBACKS[level],
+                    error: error.clone(),
+                },
+            })
+            .await;
+
+            if !retryable
+                || attempt >= self.args.retries
+                || self.cx.cancellation_token.is_cancelled()
+            {
+                return result;
+            }
+
+            let backoff = RETRY_BACKOFF
+                .saturating_mul(1 << attempt.min(5))
+                .min(RETRY_BACKOFF_MAX);
+            tracing::warn!(
+                task_id = self.id,
+                attempt = attempt + 1,
+                error = error.as_deref(),
+                "Attempt failed, retrying in {}s",
+                backoff.as_secs()
+            );
+            if tokio::time::sleep(backoff)
+                .with_cancellation_token(&self.cx.cancellation_token)
+                .await
+                .is_none()
+            {
+                return result;
+            }
+            attempt += 1;
+        }
+    }
+
+    async fn journal(&self, state: JobState) {
+        if let Some(journal) = &self.cx.journal {
+            journal.set_state(self.id, state).await;
+        }
+    }
+
+    /// Waits for the `--window` to open
+    async fn wait_for_window(&self) {
+        let Some(window) = self.args.window else {
+            return;
+        };
+        if window.is_open() {
+            return;
+        }
+
+        tracing::info!(
+            task_id = self.id,
+            "Waiting for the window to open at {}",
+            window.opens_at()
+        );
+        self.send(UiMessagePayload::WaitingForWindow {
+            opens_at: window.opens_at(),
+        })
+        .await;
+        window.wait_open().await;
+    }
+
+    /// Waits for the load average to drop below `--max-load`
+    async fn wait_for_load(&self) {
+        let Some(max) = self.args.max_load else {
+            return;
+        };
+        let mut waiting = false;
+        while let Some(load) = priority::load_average()
+            && load > max
+        {
+            if !waiting {
+                tracing::info!(task_id = self.id, load, max, "Waiting for load to drop");
+                waiting = true;
+            }
+            self.send(UiMessagePayload::WaitingForLoad { load, max })
+                .await;
+            tokio::time::sleep(priority::LOAD_POLL_INTERVAL).await;
+        }
+    }
+
+    /// What `--order smallest-first`/`largest-first` sorts by
+    async fn input_size(&self) -> u64 {
+        tokio::fs::metadata(&self.input)
+            .await
+            .map_or(0, |m| m.len())
+    }
+
+    /// Queues the task in `--order`, see `run`
+    pub async fn enqueue(&self) -> Ticket {
+        self.cx
+            .queue
+            .push(self.id, self.total_duration, self.input_size().await)
+    }
+
+    pub async fn run(self, ticket: Ticket) -> anyhow::Result<TaskOutcome> {
+        self.send(UiMessagePayload::Created {
+            input: self.input.clone(),
+            output: self.output.clone(),
+            total: self.total_duration,
+        })
+        .await;
+
+        if !self.args.force
+            && self
+                .cx
+                .fingerprints
+                .stored(&self.output, self.cx.cancellation_token.child_token())
+                .await
+                .is_some_and(|stored| stored.fingerprint == self.fingerprint)
+        {
+            tracing::info!(task_id = self.id, "Output is up to date, skipping");
+            self.journal(JobState::Completed).await;
+            self.send(UiMessagePayload::UpToDate).await;
+            return Ok(TaskOutcome::Skipped);
+        }
+        if !self.args.force
+            && self
+                .cx
+                .fingerprints
+                .discarded(&self.output, &self.fingerprint)
+                .await
+        {
+            tracing::info!(
+                task_id = self.id,
+                "Output was discarded by --keep-if-smaller last time, skipping"
+            );
+            self.journal(JobState::Completed).await;
+            self.send(UiMessagePayload::UpToDate).await;
+            return Ok(TaskOutcome::Skipped);
+        }
+
+        let slot = tokio::select! {
+            // Holding the slot while paused, so resuming doesn't start more than --parallel
+            slot = async {
+                let slot = ticket.wait().await;
+                self.wait_for_window().await;
+                self.cx.pause.wait_resumed().await;
+                self.wait_for_load().await;
+                slot
+            } => Some(slot),
+            () = self.cx.cancellation_token.cancelled() => None,
+            () = self.cx.drain_token.cancelled() => None,
+        };
+        let Some(_slot) = slot else {
+            self.journal(JobState::Cancelled).await;
+            self.send(UiMessagePayload::Cancelled).await;
+            return Ok(TaskOutcome::Cancelled);
+        };
+        self.journal(JobState::Running).await;
+        self.send(UiMessagePayload::Started).await;
+        let started_at = SystemTime::now();
+        let (pause_token, pause_handle) = self.spawn_pause_watch();
+
+        let result = self.execute().await;
+        pause_token.cancel();
+        let _ = pause_handle.await;
+        let success = result
+            .as_ref()
+            .is_ok_and(|exit| exit.exit_code.as_ref().is_some_and(|ec| ec.success));
+
+        let outcome = if success {
+            TaskOutcome::Succeeded
+        } else if self.cx.cancellation_token.is_cancelled() {
+            TaskOutcome::Cancelled
+        } else {
+            TaskOutcome::Failed
+        };
+        self.journal(match outcome {
+            TaskOutcome::Succeeded => JobState::Completed,
+            TaskOutcome::Failed => JobState::Failed,
+            TaskOutcome::Cancelled => JobState::Cancelled,
+            TaskOutcome::Skipped => JobState::Completed,
+        })
+        .await;
+        if outcome == TaskOutcome::Cancelled {
+            self.remove_partial_output(started_at).await;
+        }
+        if outcome == TaskOutcome::Succeeded {
+            self.cx
+                .fingerprints
+                .record(
+                    &self.output,
+                    &self.fingerprint,
+                    &fingerprint::source(&self.input),
+                )
+                .await;
+        }
+
+        match (result, outcome) {
+            (_, TaskOutcome::Cancelled) => self.send(UiMessagePayload::Cancelled).await,
+            (Ok(exit), _) => self.send(UiMessagePayload::Finished { exit }).await,
+            (Err(error), _) => self.send(UiMessagePayload::Failed { error }).await,
+        }
+
+        if outcome == TaskOutcome::Failed && self.args.fail_fast {
+            tracing::warn!(
+                task_id = self.id,
+                "Task failed, cancelling the rest (--fail-fast)"
+            );
+            self.cx.cancellation_token.cancel();
+        }
+
+        Ok(outcome)
+    }
+}
+
+async fn file_size(path: &Path) -> Result<u64, TaskFailure> {
+    tokio::fs::metadata(path)
+        .await
+        .map(|m| m.len())
+        .map_err(|e| {
+            TaskFailure::Verification(format!("Unable to stat \"{}\": {e}", path.display()))
+        })
 }
@@ -6,7 +6,7 @@ use std::{
 };
 
 use colored::Colorize;
-use libffmpeg::{ffmpeg::FfmpegError, util::cmd::CommandExit};
+use libffmpeg::util::cmd::CommandExit;
 use serde::Serialize;
 use std::time::Instant;
 use tokio::task::JoinHandle;
@@ -14,7 +14,13 @@ use tokio_util::{future::FutureExt, sync::CancellationToken};
 use tracing::{Instrument, Span};
 use valuable::Valuable;
 
-use crate::OutputFormat;
+use crate::{
+    OutputFormat,
+    analyze::LoudnessMeasurement,
+    quality::QualityScores,
+    search::CrfChoice,
+    tasks::{AttemptRecord, SizeReport, TaskFailure},
+};
 
 #[derive(Debug, Clone)]
 pub enum UiMessagePayload {
@@ -23,12 +29,43 @@ pub enum UiMessagePayload {
         output: PathBuf,
         total: Duration,
     },
+    /// Holding a slot, but outside `--window`
+    WaitingForWindow {
+        /// Local time, e.g. "22:00"
+        opens_at: String,
+    },
+    /// Holding a slot, but the load average is above `--max-load`
+    WaitingForLoad {
+        load: f64,
+        max: f64,
+    },
     Started,
+    /// Every running ffmpeg was stopped with SIGSTOP
+    Paused,
+    Resumed,
+    /// Skipped, the output matches the input and settings
+    UpToDate,
+    Cancelled,
     Finished {
         exit: CommandExit,
     },
     Failed {
-        error: FfmpegError,
+        error: TaskFailure,
+    },
+    Loudness {
+        measured: LoudnessMeasurement,
+    },
+    Size {
+        report: SizeReport,
+    },
+    Quality {
+        scores: QualityScores,
+    },
+    Crf {
+        choice: CrfChoice,
+    },
+    Attempt {
+        record: AttemptRecord,
     },
     Progress {
         total: Duration,
@@ -55,10 +92,24 @@ struct UiTask {
     active: bool,
     started_at: Option<Instant>,
     exited_at: Option<Instant>,
+    /// Set while paused
+    paused_at: Option<Instant>,
+    /// Time spent paused, not counting the current pause
+    paused_for: Duration,
+    /// Load average and `--max-load` while waiting for the load to drop
+    waiting_for_load: Option<(f64, f64)>,
+    /// When the window opens, while waiting for it
+    waiting_for_window: Option<String>,
     success: Option<bool>,
+    up_to_date: bool,
     error_description: Option<String>,
     total: Duration,
     current: Duration,
+    loudness: Option<LoudnessMeasurement>,
+    size: Option<SizeReport>,
+    quality: Option<QualityScores>,
+    crf: Option<CrfChoice>,
+    history: Vec<AttemptRecord>,
 }
 
 impl UiTask {
@@ -70,12 +121,35 @@ impl UiTask {
             active: false,
             started_at: None,
             exited_at: None,
+            paused_at: None,
+            paused_for: Duration::ZERO,
+            waiting_for_load: None,
+            waiting_for_window: None,
             success: None,
+            up_to_date: false,
             error_description: None,
             total,
             current: Duration::ZERO,
+            loudness: None,
+            size: None,
+            quality: None,
+            crf: None,
+            history: vec![],
+        }
+    }
+
+    fn resume(&mut self) {
+        if let Some(paused_at) = self.paused_at.take() {
+            self.paused_for += paused_at.elapsed();
         }
     }
+
+    /// Time spent running since starting, excluding pauses
+    fn active_elapsed(&self) -> Option<Duration> {
+        let elapsed = self.started_at?.elapsed();
+        let paused = self.paused_for + self.paused_at.map_or(Duration::ZERO, |i| i.elapsed());
+        Some(elapsed.saturating_sub(paused))
+    }
 }
 
 #[derive(Serialize, Valuable)]
@@ -94,15 +168,28 @@ struct TaskInfo {
     input: PathBuf,
     output: PathBuf,
     active: bool,
+    paused: bool,
+    waiting_for_load: Option<(f64, f64)>,
+    waiting_for_window: Option<String>,
     started_at: Option<String>,
     elapsed: Option<String>,
     eta: Option<String>,
     exited_at: Option<String>,
     success: Option<bool>,
+    up_to_date: bool,
     error_description: Option<String>,
     total: String,
     current: String,
     percent: String,
+    loudness: Option<LoudnessMeasurement>,
+    size: Option<SizeReport>,
+    quality: Option<QualityScores>,
+    crf: Option<CrfChoice>,
+    history: Vec<AttemptRecord>,
+}
+
+pub fn format_size(bytes: u64) -> String {
+    format!("{:.1} MB", bytes as f64 / 1_000_000.0)
 }
 
 impl Row {
@@ -154,6 +241,9 @@ impl Row {
 
             let _ = write!(output, "{}[{}", filename.cyan(), percent_colored,);
 
+            if task.paused {
+                let _ = write!(output, " {}", "paused".yellow());
+            }
             if let Some(ref eta) = task.eta {
                 let _ = write!(output, " {}", format!("eta: {eta}").dimmed());
             }
@@ -172,6 +262,135 @@ impl Row {
             );
         }
 
+        let waiting: Vec<_> = self
+            .tasks
+            .iter()
+            .filter_map(|t| t.waiting_for_load)
+            .collect();
+        let waiting_for_window: Vec<_> = self
+            .tasks
+            .iter()
+            .filter_map(|t| t.waiting_for_window.as_deref())
+            .collect();
+        if let Some(opens_at) = waiting_for_window.first() {
+            let _ = write!(
+                output,
+                " | {}",
+                format!(
+                    "{} waiting for window (opens {opens_at})",
+                    waiting_for_window.len()
+                )
+                .yellow()
+            );
+        }
+
+        if let Some((load, max)) = waiting.first() {
+            let _ = write!(
+                output,
+                " | {}",
+                format!("{} waiting (load {load:.2} > {max:.2})", waiting.len()).yellow()
+            );
+        }
+
+        output
+    }
+
+    fn to_string_summary(&self) -> String {
+        use std::fmt::Write;
+
+        let mut output = String::with_capacity(256);
+        let _ = writeln!(
+            output,
+            "\n{} | {} | {}",
+            format!("T: {}", self.total_tasks).bold(),
+            format!("S: {}", self.successful_tasks).green(),
+            format!("F: {}", self.failed_tasks).red(),
+        );
+
+        for task in &self.tasks {
+            let status = match task.success {
+                Some(true) if task.up_to_date => "up to date".dimmed(),
+                Some(true) => "ok".green(),
+                Some(false) => "failed".red(),
+                None if task.started_at.is_some() => "cancelled".yellow(),
+                None if task.exited_at.is_some() => "not started".yellow(),
+                None => "skipped".dimmed(),
+            };
+            let _ = write!(
+                output,
+                "{status} {} -> {}",
+                task.input.display(),
+                task.output.display()
+            );
+
+            if let Some(ref elapsed) = task.elapsed {
+                let _ = write!(output, " {}", format!("({elapsed})").dimmed());
+            }
+            if let Some(loudness) = task.loudness {
+                let _ = write!(
+                    output,
+                    " {}",
+                    format!(
+                        "[I: {:.1} LUFS, TP: {:.1} dBTP, LRA: {:.1} LU]",
+                        loudness.integrated, loudness.true_peak, loudness.lra
+                    )
+                    .dimmed()
+                );
+            }
+            if let Some(crf) = task.crf {
+                let _ = write!(
+                    output,
+                    " {}",
+                    format!("[crf {} ~{}]", crf.crf, format_size(crf.predicted_size)).dimmed()
+                );
+            }
+            if let Some(quality) = task.quality {
+                let scores: Vec<String> = [
+                    ("SSIM", quality.ssim.map(|s| format!("{s:.4}"))),
+                    ("PSNR", quality.psnr.map(|s| format!("{s:.2}dB"))),
+                    ("VMAF", quality.vmaf.map(|s| format!("{s:.2}"))),
+                ]
+                .into_iter()
+                .filter_map(|(name, score)| Some(format!("{name}: {}", score?)))
+                .collect();
+                let _ = write!(output, " {}", format!("[{}]", scores.join(", ")).dimmed());
+            }
+            if let Some(size) = task.size {
+                let _ = write!(
+                    output,
+                    " {}",
+                    format!(
+                        "[{} -> {}, {:?}]",
+                        format_size(size.input_size),
+                        format_size(size.output_size),
+                        size.decision
+                    )
+                    .dimmed()
+                );
+            }
+            if let Some(ref error) = task.error_description {
+                let _ = write!(output, " {}", error.red());
+            }
+            output.push('\n');
+
+            // Only worth listing when something was retried
+            if task.history.len() > 1 {
+                for record in &task.history {
+                    let _ = writeln!(
+                        output,
+                        "    {}",
+                        format!(
+                            "attempt {} (fallback: {}): {}",
+                            record.attempt,
+                            record.fallback,
+                            record.error.as_deref().unwrap_or("ok")
+                        )
+                        .dimmed()
+                    );
+                }
+            }
+        }
+
         output
     }
 
@@ -236,16 +455,43 @@ impl UiState {
             let task = task.as_mut().unwrap();
             match delivery.payload {
                 UiMessagePayload::Created { .. } => { /* nop, should be unreachable */ }
+                UiMessagePayload::WaitingForWindow { opens_at } => {
+                    task.waiting_for_window = Some(opens_at);
+                }
+                UiMessagePayload::WaitingForLoad { load, max } => {
+                    task.waiting_for_window = None;
+                    task.waiting_for_load = Some((load, max));
+                }
                 UiMessagePayload::Started => {
+                    task.waiting_for_load = None;
+                    task.waiting_for_window = None;
                     task.active = true;
                     task.started_at = Some(Instant::now());
                 }
+                UiMessagePayload::UpToDate => {
+                    task.exited_at = Some(Instant::now());
+                    task.success = Some(true);
+                    task.up_to_date = true;
+                }
+                UiMessagePayload::Paused => {
+                    task.paused_at.get_or_insert_with(Instant::now);
+                }
+                UiMessagePayload::Resumed => task.resume(),
+                UiMessagePayload::Cancelled => {
+                    task.resume();
+                    task.waiting_for_load = None;
+                    task.waiting_for_window = None;
+                    task.active = false;
+                    task.exited_at = Some(Instant::now());
+                }
                 UiMessagePayload::Finished { exit } => {
+                    task.resume();
                     task.active = false;
                     task.exited_at = Some(Instant::now());
                     task.success = Some(exit.exit_code.is_some_and(|ec| ec.success));
                 }
                 UiMessagePayload::Failed { error } => {
+                    task.resume();
                     task.active = false;
                     task.exited_at = Some(Instant::now());
                     task.success = Some(false);
@@ -255,13 +501,28 @@ impl UiState {
                     task.current = current;
                     task.total = total;
                 }
+                UiMessagePayload::Loudness { measured } => {
+                    task.loudness = Some(measured);
+                }
+                UiMessagePayload::Size { report } => {
+                    task.size = Some(report);
+                }
+                UiMessagePayload::Quality { scores } => {
+                    task.quality = Some(scores);
+                }
+                UiMessagePayload::Crf { choice } => {
+                    task.crf = Some(choice);
+                }
+                UiMessagePayload::Attempt { record } => {
+                    task.history.push(record);
+                }
             }
         }
 
         Ok(())
     }
 
-    pub fn draw(&self, stdout: &mut StdoutLock, format: OutputFormat) -> anyhow::Result<()> {
+    fn row(&self) -> Row {
         let tasks: Vec<TaskInfo> = self
             .tasks
             .iter()
@@ -271,6 +532,9 @@ impl UiState {
                 input: t.input.clone(),
                 output: t.output.clone(),
                 active: t.active,
+                paused: t.paused_at.is_some(),
+                waiting_for_load: t.waiting_for_load,
+                waiting_for_window: t.waiting_for_window.clone(),
                 started_at: t
                     .started_at
                     .map(|i| format!("T-{:.0}", Instant::now().duration_since(i).as_secs_f64())),
@@ -278,8 +542,11 @@ impl UiState {
                     let d = Instant::now().duration_since(i);
                     format!("{}m {}s", d.as_secs() / 60, d.as_secs() % 60)
                 }),
-                eta: t.started_at.and_then(|i| {
-                    let elapsed = Instant::now().duration_since(i).as_secs_f64();
+                eta: t.active_elapsed().and_then(|elapsed| {
+                    if t.paused_at.is_some() {
+                        return None;
+                    }
+                    let elapsed = elapsed.as_secs_f64();
                     let progress = t.current.as_secs_f64();
 
                     if progress < t.total.as_secs_f64() * 0.01 {
@@ -299,6 +566,7 @@ impl UiState {
                     .exited_at
                     .map(|i| format!("T-{:.0}", Instant::now().duration_since(i).as_secs_f64())),
                 success: t.success,
+                up_to_date: t.up_to_date,
                 error_description: t.error_description.clone(),
                 total: format!("{:.1}s", t.total.as_secs_f64()),
                 current: format!("{:.1}s", t.current.as_secs_f64()),
@@ -307,18 +575,26 @@ impl UiState {
                     (t.current.as_secs_f64() / t.total.as_secs_f64().max(f64::EPSILON) * 100.0)
                         .min(100.0)
                 ),
+                loudness: t.loudness,
+                size: t.size,
+                quality: t.quality,
+                crf: t.crf,
+                history: t.history.clone(),
             })
             .collect();
 
-        let row: Row = Row {
+        Row {
             total_tasks: tasks.len(),
             active_tasks: tasks.iter().filter(|t| t.active).count(),
             completed_tasks: tasks.iter().filter(|t| t.exited_at.is_some()).count(),
             successful_tasks: tasks.iter().filter(|t| t.success == Some(true)).count(),
             failed_tasks: tasks.iter().filter(|t| t.success == Some(false)).count(),
             tasks,
-        };
+        }
+    }
 
+    pub fn draw(&self, stdout: &mut StdoutLock, format: OutputFormat) -> anyhow::Result<()> {
+        let row = self.row();
         let output = match format {
             OutputFormat::Human => row.to_string_human(),
             OutputFormat::Json => row.to_string_json(),
@@ -331,6 +607,22 @@ impl UiState {
         writeln!(stdout, "{output}")?;
         Ok(())
     }
+
+    /// Final per-task report, printed once every task has exited
+    pub fn summarize(&self, stdout: &mut StdoutLock, format: OutputFormat) -> anyhow::Result<()> {
+        let row = self.row();
+        let output = match format {
+            OutputFormat::Human => row.to_string_summary(),
+            OutputFormat::Json => row.to_string_json(),
+            OutputFormat::JsonPretty => row.to_string_json_pretty(),
+            OutputFormat::Verbose => {
+                tracing::info!(summary = row.as_value(), "Finished all tasks");
+                return Ok(());
+            }
+        };
+        writeln!(stdout, "{output}")?;
+        Ok(())
+    }
 }
 
 pub async fn ui_main(
@@ -374,6 +666,15 @@ pub async fn ui_main(
         }
     }
 
+    // Pick up anything sent between the last poll and cancellation
+    while let Ok(delivery) = rx.try_recv() {
+        state.update(delivery)?;
+    }
+
+    let mut stdout = stdout().lock();
+    state.summarize(&mut stdout, format)?;
+    stdout.flush()?;
+
     Ok(())
 }
 
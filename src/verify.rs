@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use crate::probe::Probe;
+
+/// Allowed drift between input and output duration, whichever is larger
+const DURATION_TOLERANCE: Duration = Duration::from_secs(1);
+const DURATION_TOLERANCE_RATIO: f64 = 0.02;
+
+/// What a successfully encoded output should look like
+#[derive(Debug, Clone, Default)]
+pub struct Expectations {
+    pub duration: Duration,
+    pub video_codec: Option<String>,
+    /// `None` when the output should have no audio
+    pub audio_codec: Option<String>,
+}
+
+impl Expectations {
+    /// Compares a probed output against the expectations, returning every mismatch found
+    pub fn verify(&self, probe: &Probe) -> Vec<String> {
+        let mut mismatches = vec![];
+
+        match probe.duration() {
+            Some(duration) => {
+                let tolerance =
+                    DURATION_TOLERANCE.max(self.duration.mul_f64(DURATION_TOLERANCE_RATIO));
+                if duration.abs_diff(self.duration) > tolerance {
+                    mismatches.push(format!(
+                        "duration is {:.1}s, expected {:.1}s",
+                        duration.as_secs_f64(),
+                        self.duration.as_secs_f64()
+                    ));
+                }
+            }
+            None => mismatches.push("duration is unknown".to_string()),
+        }
+
+        for (kind, expected, streams) in [
+            ("video", &self.video_codec, probe.streams_of("video")),
+            ("audio", &self.audio_codec, probe.streams_of("audio")),
+        ] {
+            let expected_count = usize::from(expected.is_some());
+            if streams.len() != expected_count {
+                mismatches.push(format!(
+                    "{} {kind} streams, expected {expected_count}",
+                    streams.len()
+                ));
+                continue;
+            }
+
+            if let (Some(expected), Some(stream)) = (expected, streams.first())
+                && stream.codec_name.as_ref() != Some(expected)
+            {
+                mismatches.push(format!(
+                    "{kind} codec is {}, expected {expected}",
+                    stream.codec_name.as_deref().unwrap_or("unknown")
+                ));
+            }
+        }
+
+        mismatches
+    }
+}
@@ -0,0 +1,196 @@
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use chrono::{Local, Timelike};
+use tokio_util::sync::CancellationToken;
+use valuable::Valuable;
+
+use crate::pause::PauseControl;
+
+const DAY_SECS: u32 = 24 * 60 * 60;
+/// Upper bound on sleeps while waiting for the window, so clock changes are noticed
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// `--window`, a daily local time range like "22:00-07:00". Ranges past midnight wrap, and
+/// equal start and end means always open
+#[derive(Debug, Clone, Copy, Valuable)]
+pub struct TimeWindow {
+    /// Seconds since local midnight
+    start: u32,
+    end: u32,
+}
+
+fn parse_time(s: &str) -> Result<u32, String> {
+    let (hours, minutes) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected HH:MM, got \"{s}\""))?;
+    let hours: u32 = hours
+        .parse()
+        .map_err(|e| format!("invalid hour \"{hours}\": {e}"))?;
+    let minutes: u32 = minutes
+        .parse()
+        .map_err(|e| format!("invalid minute \"{minutes}\": {e}"))?;
+    if hours > 23 || minutes > 59 {
+        return Err(format!("\"{s}\" is not a time of day"));
+    }
+    Ok((hours * 60 + minutes) * 60)
+}
+
+impl FromStr for TimeWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once('-')
+            .ok_or_else(|| format!("expected HH:MM-HH:MM, got \"{s}\""))?;
+        Ok(Self {
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        })
+    }
+}
+
+fn now_secs() -> u32 {
+    Local::now().num_seconds_from_midnight()
+}
+
+impl TimeWindow {
+    fn contains(self, secs: u32) -> bool {
+        if self.start <= self.end {
+            self.start == self.end || (self.start..self.end).contains(&secs)
+        } else {
+            secs >= self.start || secs < self.end
+        }
+    }
+
+    pub fn is_open(self) -> bool {
+        self.contains(now_secs())
+    }
+
+    /// Time until the window next opens, zero if it's open
+    pub fn until_open(self) -> Duration {
+        self.until_open_from(now_secs())
+    }
+
+    fn until_open_from(self, now: u32) -> Duration {
+        if self.contains(now) {
+            return Duration::ZERO;
+        }
+        Duration::from_secs(u64::from((self.start + DAY_SECS - now) % DAY_SECS))
+    }
+
+    /// Local time the window next opens, e.g. "22:00"
+    pub fn opens_at(self) -> String {
+        format!("{:02}:{:02}", self.start / 3600, self.start % 3600 / 60)
+    }
+
+    /// Sleeps until the window is open
+    pub async fn wait_open(self) {
+        loop {
+            let until = self.until_open();
+            if until.is_zero() {
+                return;
+            }
+            tokio::time::sleep(until.min(MAX_SLEEP)).await;
+        }
+    }
+}
+
+/// Pauses running tasks while the window is closed and resumes them when it opens
+pub fn spawn_pauser(window: TimeWindow, pause: Arc<PauseControl>, ct: CancellationToken) {
+    tokio::spawn(async move {
+        let mut was_open = true;
+        while !ct.is_cancelled() {
+            let open = window.is_open();
+            if open != was_open {
+                tracing::info!(
+                    open,
+                    "Window {}, {} running tasks",
+                    if open { "opened" } else { "closed" },
+                    if open { "resuming" } else { "pausing" }
+                );
+                pause.set_window_closed(!open);
+                was_open = open;
+            }
+
+            tokio::select! {
+                () = tokio::time::sleep(MAX_SLEEP) => {}
+                () = ct.cancelled() => break,
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hours: u32, minutes: u32) -> u32 {
+        (hours * 60 + minutes) * 60
+    }
+
+    fn window(s: &str) -> TimeWindow {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses() {
+        let w = window("22:00-07:30");
+        assert_eq!((w.start, w.end), (at(22, 0), at(7, 30)));
+        assert_eq!(w.opens_at(), "22:00");
+    }
+
+    #[test]
+    fn rejects_bad_times() {
+        for s in [
+            "22:00",
+            "24:00-07:00",
+            "22:60-07:00",
+            "22-07",
+            "ab:cd-07:00",
+            "",
+        ] {
+            assert!(s.parse::<TimeWindow>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn same_day_range_includes_start_excludes_end() {
+        let w = window("09:00-17:00");
+        assert!(!w.contains(at(8, 59)));
+        assert!(w.contains(at(9, 0)));
+        assert!(w.contains(at(16, 59)));
+        assert!(!w.contains(at(17, 0)));
+    }
+
+    #[test]
+    fn wraps_past_midnight() {
+        let w = window("22:00-07:00");
+        assert!(w.contains(at(22, 0)));
+        assert!(w.contains(at(23, 59)));
+        assert!(w.contains(at(0, 0)));
+        assert!(w.contains(at(6, 59)));
+        assert!(!w.contains(at(7, 0)));
+        assert!(!w.contains(at(21, 59)));
+    }
+
+    #[test]
+    fn equal_ends_are_always_open() {
+        let w = window("08:00-08:00");
+        assert!(w.contains(at(0, 0)));
+        assert!(w.contains(at(8, 0)));
+        assert!(w.contains(at(23, 59)));
+    }
+
+    #[test]
+    fn until_open() {
+        let w = window("22:00-07:00");
+        assert_eq!(w.until_open_from(at(23, 0)), Duration::ZERO);
+        assert_eq!(w.until_open_from(at(21, 0)), Duration::from_secs(3600));
+        assert_eq!(w.until_open_from(at(7, 0)), Duration::from_secs(15 * 3600));
+
+        // Opens tomorrow once today's start has passed
+        let w = window("01:00-02:00");
+        assert_eq!(w.until_open_from(at(2, 0)), Duration::from_secs(23 * 3600));
+        assert_eq!(w.until_open_from(at(0, 0)), Duration::from_secs(3600));
+    }
+}